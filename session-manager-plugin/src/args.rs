@@ -60,7 +60,7 @@ impl StartSessionParams {
     fn process_opname(arg: String) -> Result<String, Error> {
         match arg.as_str() {
             "StartSession" => Ok(arg),
-            _ => Err(Error::UnknownOperation(arg.to_string())),
+            _ => Err(Error::UnknownOperation(arg)),
         }
     }
 
@@ -9,13 +9,14 @@ pub enum Command {
 }
 
 impl Command {
-    pub async fn execute(self) -> Result<(), crate::Error> {
+    /// Execute the command, returning the code the process should exit with.
+    pub async fn execute(self) -> Result<i32, crate::Error> {
         match self {
             Command::ReportInstallSuccess => report_install_success(),
             Command::Version => report_version(),
-            Command::StartSession(args) => start_session(args).await?,
+            Command::StartSession(args) => return start_session(args).await,
         }
-        Ok(())
+        Ok(0)
     }
 }
 
@@ -29,10 +30,12 @@ fn report_version() {
     println!("{}", env!("CARGO_PKG_VERSION"));
 }
 
-async fn start_session(args: StartSessionParams) -> Result<(), crate::Error> {
+/// Run the session. Exits with the remote command's exit code when the agent reports one, so that callers
+/// can detect failed non-interactive commands.
+async fn start_session(args: StartSessionParams) -> Result<i32, crate::Error> {
     // Allow deprecated usage of `with_aws_cli_upgrade_needed` for compatibility with the original implementation.
     #[allow(deprecated)]
-    let session = SessionBuilder::new()
+    let mut session = SessionBuilder::new()
         .with_stream_url(args.response.stream_url)
         .with_endpoint(args.ssm_endpoint)
         .with_aws_cli_upgrade_needed(args.is_aws_cli_upgrade_needed)
@@ -40,9 +43,9 @@ async fn start_session(args: StartSessionParams) -> Result<(), crate::Error> {
         .with_target_id(args.target)
         .build();
 
-    session.execute().await?;
+    let outcome = session.execute().await?;
 
     // TODO: Implement the rest of the session creation logic
     // TODO: Implement session handling logic
-    Ok(())
+    Ok(outcome.exit_code.unwrap_or(0))
 }
@@ -31,7 +31,7 @@ async fn main() {
     };
 
     match command.execute().await {
-        Ok(()) => exit(0),
+        Ok(exit_code) => exit(exit_code),
         Err(err) => {
             eprintln!("{err}");
             exit(1)
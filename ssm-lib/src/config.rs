@@ -16,6 +16,13 @@ pub const DATA_CHANNEL_RETRY_MAX_INTERVAL_MILLIS: u64 = 5000;
 /// Used to define the maximum number of retries for the exponential backoff algorithm.
 pub const DATA_CHANNEL_NUM_MAX_RETRIES: u64 = 5;
 
+/// The interval between websocket ping frames, which keep idle connections alive through NAT devices
+/// and proxies.
+pub const PING_TIME_INTERVAL_MILLIS: u64 = 5 * 60 * 1000;
+
+/// How long to wait for the pong answering a websocket ping before treating the connection as dead.
+pub const PONG_TIMEOUT_MILLIS: u64 = 30 * 1000;
+
 /// TODO: document
 pub const ROLE_PUBLISH_SUBSCRIBE: &str = "publish_subscribe";
 
@@ -34,8 +41,46 @@ pub const DEFAULT_ROUND_TRIP_TIME_VARIATION_MILLIS: u64 = 0;
 /// TODO: document
 pub const DEFAULT_TRANSMISSION_TIMEOUT_MILLIS: u64 = 200;
 
+/// The lower bound of the retransmission timeout. Resending sooner than the outgoing message buffer is
+/// scanned, every [`RESEND_SLEEP_INTERVAL_MILLIS`], would have no effect.
+pub const MIN_TRANSMISSION_TIMEOUT_MILLIS: u64 = 100;
+
+/// The upper bound of the retransmission timeout.
+pub const MAX_TRANSMISSION_TIMEOUT_MILLIS: u64 = 1000;
+
+/// The weight of a new round trip time measurement in the smoothed round trip time (alpha).
+pub const RTT_CONSTANT: f64 = 1.0 / 8.0;
+
+/// The weight of a new round trip time measurement in the round trip time variation (beta).
+pub const RTTV_CONSTANT: f64 = 1.0 / 4.0;
+
+/// How often the outgoing message buffer is scanned for messages to resend.
+pub const RESEND_SLEEP_INTERVAL_MILLIS: u64 = 100;
+
+/// How many times a stream data message is resent before the session is abandoned. The original
+/// implementation derives it from five minutes of resending every [`RESEND_SLEEP_INTERVAL_MILLIS`], but as
+/// there a message is only resent once the retransmission timeout has expired, so giving up takes longer.
+pub const RESEND_MAX_ATTEMPT: u32 = 3000;
+
+/// The clock granularity, which is the least amount added to the smoothed round trip time to get the
+/// retransmission timeout.
+pub const CLOCK_GRANULARITY_MILLIS: u64 = 10;
+
 /// TODO: document
 pub const MESSAGE_SCHEMA_VERSION: &str = "1.0";
 
 /// TODO: document
 pub const CLIENT_VERSION: &str = "1.0.0";
+
+/// The session type used by shell sessions. Interactive and non-interactive command sessions are handled
+/// as shell sessions too.
+pub const SHELL_PLUGIN_NAME: &str = "Standard_Stream";
+
+/// The session type used by interactive command sessions.
+pub const INTERACTIVE_COMMANDS_PLUGIN_NAME: &str = "InteractiveCommands";
+
+/// The session type used by non-interactive command sessions.
+pub const NON_INTERACTIVE_COMMANDS_PLUGIN_NAME: &str = "NonInteractiveCommands";
+
+/// The session type used by port forwarding sessions.
+pub const PORT_PLUGIN_NAME: &str = "Port";
@@ -2,110 +2,216 @@
 
 use crate::{
     config,
-    message::{self, MessageType},
+    encryption::{DynKmsClient, Encrypter, KmsClient},
+    message::{
+        self, ActionStatus, ActionType, ClientMessage, ClientMessageRef,
+        EncryptionChallengeResponse, HandshakeRequestPayload, HandshakeResponsePayload,
+        KmsEncryptionRequest, KmsEncryptionResponse, MessageType, PayloadType,
+        ProcessedClientAction, SessionTypeRequest,
+    },
     service,
-    websocket_channel::{DefaultWebsocketChannel, WebsocketChannel},
+    sync::lock,
+    websocket_channel::{
+        BINARY_MESSAGE, DefaultWebsocketChannel, IncomingMessages, TEXT_MESSAGE, WebsocketChannel,
+    },
 };
 use std::{
-    cell::RefCell,
     collections::{HashMap, VecDeque},
+    convert::Infallible,
     fmt::Debug,
+    future::Future,
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::Duration,
 };
+use tokio::time::Instant;
 
 /// TODO: Add a description of the data channel.
 #[mockall::automock]
 #[allow(clippy::ref_option_ref)] // warning in generated code
-pub trait DataChannel {
+pub trait DataChannel: Send + Sync {
     /// TODO: document
     ///
     /// ## Errors
     ///
     /// TODO: doc errors
-    fn reconnect(&self) -> Result<(), crate::Error>;
+    fn reconnect(&self) -> impl Future<Output = Result<(), crate::Error>> + Send;
 
     /// TODO: document
     ///
     /// ## Errors
     ///
     /// TODO: document errors
-    fn close(&self) -> Result<(), crate::Error>;
+    fn close(&self) -> impl Future<Output = Result<(), crate::Error>> + Send;
 
     /// TODO: document
     ///
     /// ## Errors
     ///
     /// TODO: doc errors
-    fn open(&self) -> Result<(), crate::Error>;
+    fn open(&self) -> impl Future<Output = Result<(), crate::Error>> + Send;
 
     /// TODO: document
     ///
     /// ## Errors
     ///
     /// TODO: doc errors
-    fn finalize_data_channel_handshake(&self, channel_token: &str) -> Result<(), crate::Error>;
+    fn finalize_data_channel_handshake(
+        &self,
+        channel_token: &str,
+    ) -> impl Future<Output = Result<(), crate::Error>> + Send;
 
     /// TODO: document
     ///
     /// ## Errors
     ///
     /// TODO: doc errors
-    fn send_message(&self, input: &[u8], input_type: u32) -> Result<(), crate::Error>;
+    fn send_message(
+        &self,
+        input: &[u8],
+        input_type: u32,
+    ) -> impl Future<Output = Result<(), crate::Error>> + Send;
 
-    /// TODO: document
+    /// Send `input_data` to the agent in an `input_stream_data` message with the next sequence number, and
+    /// keep it in the outgoing message buffer until the agent acknowledges it.
     ///
     /// ## Errors
     ///
-    /// TODO: doc errors
+    /// Returns an error if the message cannot be built or sent. A message which was built but could not
+    /// be sent stays in the outgoing message buffer, so it is resent with its sequence number.
     fn send_input_data_message(
         &self,
         payload_type: message::PayloadType,
         input_data: &[u8],
-    ) -> Result<(), crate::Error>;
+    ) -> impl Future<Output = Result<(), crate::Error>> + Send;
+
+    /// Send a flag to the agent, for example to terminate the session or to close a port forwarding
+    /// connection.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if the flag message cannot be sent.
+    fn send_flag(
+        &self,
+        flag: message::PayloadTypeFlag,
+    ) -> impl Future<Output = Result<(), crate::Error>> + Send;
 
     /// TODO: document
     fn add_data_to_outgoing_message_buffer(&self, streaming_message: StreamingMessage);
 
-    /// Although we always remove messages from the front of the queue, for concurrency reasons
-    /// we need to provide the message to ensure that we remove only the message we aimed to remove.
-    /// This is because the application logic will read an item from the buffer, process it, and then
-    /// remove it. However, the item may be removed by another thread while it is being processed.
-    /// This means we need to confirm that the item we want to remove from the front of the buffer
-    /// is actually the one we took first.
+    /// Remove the message with `sequence_number` from the outgoing message buffer, once the agent has
+    /// acknowledged it. Messages are identified by sequence number rather than position, since
+    /// acknowledgements may arrive in any order.
+    ///
+    /// Returns the removed message, or `None` if no buffered message has that sequence number.
+    fn remove_data_from_outgoing_message_buffer(
+        &self,
+        sequence_number: u64,
+    ) -> Option<StreamingMessage>;
+
+    /// Remove the acknowledged message from the outgoing message buffer, and update the round trip time
+    /// estimate with the time it took to be acknowledged. Acknowledgements for messages which are not
+    /// buffered, for example because they were already acknowledged, are ignored.
+    ///
+    /// Only messages which were not resent are measured, since the acknowledgement of a resent message
+    /// cannot be matched to one of its copies.
+    fn process_acknowledged_message(&self, acknowledge_content: &message::AcknowledgeContent);
+
+    /// Resend stream data messages which have not been acknowledged within the retransmission timeout,
+    /// checking every [`config::RESEND_SLEEP_INTERVAL_MILLIS`]. Run it alongside
+    /// [`DataChannel::receive_events`] for as long as the session is open, so messages lost while the
+    /// connection was down are delivered once it is back.
+    ///
+    /// ## Errors
+    ///
+    /// Only returns, with [`crate::Error::StreamMessageResendTimeout`], once a message has been resent
+    /// [`config::RESEND_MAX_ATTEMPT`] times without being acknowledged. Failures to send are logged and
+    /// retried.
+    fn resend_stream_data_message_scheduler(
+        &self,
+    ) -> impl Future<Output = Result<Infallible, crate::Error>> + Send;
+
+    /// Process a raw message received from the agent. Stream data messages are acknowledged and
+    /// delivered in sequence order; messages which arrive ahead of the expected sequence number are
+    /// buffered until the gap is filled. A stream data message which cannot be processed is logged and
+    /// skipped, so that the messages behind it are still delivered.
+    ///
+    /// Returns the events produced by the message, in the order they should be handled.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if the message cannot be parsed or if an acknowledgement cannot be sent.
+    fn output_message_handler(
+        &self,
+        raw_message: &[u8],
+    ) -> impl Future<Output = Result<Vec<DataChannelEvent>, crate::Error>> + Send;
+
+    /// Wait for the next message from the agent and process it with
+    /// [`DataChannel::output_message_handler`]. Messages keep arriving across a reconnect. A connection
+    /// which stops answering keepalive pings is presumed dead and reconnected with
+    /// [`DataChannel::reconnect`].
+    ///
+    /// ## Errors
     ///
-    /// I feel like there could be some way to improve the performance by altering this logic, but will
-    /// wait until the implementation is complete so we can set up some performance testing before
-    /// making changes.
-    fn remove_data_from_outgoing_message_buffer<'a>(
-        &'a self,
-        streaming_message: Option<&'a StreamingMessage>,
-    );
+    /// Returns the error which ended the websocket connection, [`crate::Error::WebsocketClosed`] if the
+    /// remote end closed it, [`crate::Error::Reconnect`] if a dead connection could not be replaced, or
+    /// an error if the message could not be processed.
+    fn receive_events(
+        &self,
+    ) -> impl Future<Output = Result<Vec<DataChannelEvent>, crate::Error>> + Send;
+}
+
+/// An event produced by the [`DataChannel`] while processing messages received from the agent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DataChannelEvent {
+    /// Stream data from the agent, delivered in sequence order.
+    StreamData {
+        /// The type of data contained in the payload.
+        payload_type: message::PayloadType,
+        /// The payload of the stream data message.
+        payload: Vec<u8>,
+    },
+    /// The exit code of the remote command, sent by the agent when a non-interactive command finishes.
+    ExitCode(i32),
+    /// A flag sent by the agent.
+    Flag(message::PayloadTypeFlag),
+    /// The handshake with the agent is complete and the session can start.
+    HandshakeComplete {
+        /// The session type negotiated during the handshake, if the agent requested one.
+        session_type: Option<SessionTypeRequest>,
+        /// A message from the agent to show the user. May be empty.
+        customer_message: String,
+    },
+    /// The agent closed the channel. No further messages will be received for this session.
+    ChannelClosed(message::ChannelClosed),
 }
 
 /// TODO: Add a description of the default data channel.
-#[derive(Default)]
 pub struct DefaultDataChannel<Channel = DefaultWebsocketChannel>
 where
     Channel: WebsocketChannel,
 {
     role: String,
     client_id: String,
-    expected_sequence_number: u32,
-    /// Use [`RefCell`] to allow interior mutability since callers do not need to know
+    expected_sequence_number: Mutex<u32>,
+    /// Use [`Mutex`] to allow interior mutability since callers do not need to know
     /// or care about the mutability of this field as it is an internal implementation detail.
     /// May consider the runtime cost of this in the future.
-    stream_data_sequence_number: RefCell<u32>,
+    stream_data_sequence_number: Mutex<u32>,
     outgoing_message_buffer: Arc<Mutex<ListMessageBuffer>>,
-    incoming_message_buffer: MapMessageBuffer,
-    round_trip_time: Duration,
-    round_trip_time_variation: Duration,
-    retransmission_timeout: Duration,
+    incoming_message_buffer: Mutex<MapMessageBuffer>,
+    round_trip_times: Mutex<RoundTripTimes>,
     ws_channel: Channel,
+    /// Messages received by `ws_channel`, which the data channel subscribes to when it is created.
+    incoming: tokio::sync::Mutex<IncomingMessages>,
     session_id: String,
     instance_id: String,
     is_aws_cli_upgrade_needed: bool, // TODO: I don't like that this is here; feels like an outer layer should track and handle this
-    encryption_enabled: bool,
+    kms_client: Option<Box<dyn DynKmsClient>>,
+    /// Set once the agent has requested KMS encryption during the handshake.
+    encrypter: Mutex<Option<Encrypter>>,
+    agent_version: Mutex<String>,
+    session_type: Mutex<Option<SessionTypeRequest>>,
     /// The original Go project allowed replacing `send_message` at runtime in tests to inject some additional
     /// tracking logic. This is an attempt to reproduce this behavior without impacting runtime performance.
     #[cfg(test)]
@@ -128,14 +234,19 @@ where
             )
             .field("outgoing_message_buffer", &self.outgoing_message_buffer)
             .field("incoming_message_buffer", &self.incoming_message_buffer)
-            .field("round_trip_time", &self.round_trip_time)
-            .field("round_trip_time_variation", &self.round_trip_time_variation)
-            .field("retransmission_timeout", &self.retransmission_timeout)
+            .field("round_trip_times", &self.round_trip_times)
             .field("ws_channel", &self.ws_channel)
+            .field("incoming", &self.incoming)
             .field("session_id", &self.session_id)
             .field("instance_id", &self.instance_id)
             .field("is_aws_cli_upgrade_needed", &self.is_aws_cli_upgrade_needed)
-            .field("encryption_enabled", &self.encryption_enabled);
+            .field(
+                "kms_client",
+                &self.kms_client.as_ref().map(|_| "dyn KmsClient"),
+            )
+            .field("encrypter", &self.encrypter)
+            .field("agent_version", &self.agent_version)
+            .field("session_type", &self.session_type);
 
         #[cfg(test)]
         it.field(
@@ -168,22 +279,20 @@ impl DefaultDataChannel {
         DefaultDataChannel {
             role: config::ROLE_PUBLISH_SUBSCRIBE.to_string(),
             client_id,
-            expected_sequence_number: Self::INITIAL_EXPECTED_SEQUENCE_NUMBER,
-            stream_data_sequence_number: RefCell::new(Self::INITIAL_STREAM_DATA_SEQUENCE_NUMBER),
+            expected_sequence_number: Mutex::new(Self::INITIAL_EXPECTED_SEQUENCE_NUMBER),
+            stream_data_sequence_number: Mutex::new(Self::INITIAL_STREAM_DATA_SEQUENCE_NUMBER),
             outgoing_message_buffer: Arc::new(Mutex::new(ListMessageBuffer::default())),
-            incoming_message_buffer: MapMessageBuffer::default(),
-            round_trip_time: Duration::from_millis(config::DEFAULT_ROUND_TRIP_TIME_MILLIS),
-            round_trip_time_variation: Duration::from_millis(
-                config::DEFAULT_ROUND_TRIP_TIME_VARIATION_MILLIS,
-            ),
-            retransmission_timeout: Duration::from_millis(
-                config::DEFAULT_TRANSMISSION_TIMEOUT_MILLIS,
-            ),
+            incoming_message_buffer: Mutex::new(MapMessageBuffer::new()),
+            round_trip_times: Mutex::default(),
+            incoming: tokio::sync::Mutex::new(ws_channel.subscribe()),
             ws_channel,
             session_id,
             instance_id,
             is_aws_cli_upgrade_needed: false,
-            encryption_enabled: false,
+            kms_client: None,
+            encrypter: Mutex::default(),
+            agent_version: Mutex::default(),
+            session_type: Mutex::default(),
             #[cfg(test)]
             send_message_test_hook: None,
         }
@@ -200,16 +309,30 @@ impl DefaultDataChannel {
     }
 }
 
+impl<Channel> Default for DefaultDataChannel<Channel>
+where
+    Channel: Default + WebsocketChannel,
+{
+    fn default() -> Self {
+        DefaultDataChannel::new(
+            String::new(),
+            String::new(),
+            String::new(),
+            Channel::default(),
+        )
+    }
+}
+
 impl<Channel> DataChannel for DefaultDataChannel<Channel>
 where
     Channel: WebsocketChannel,
 {
-    fn reconnect(&self) -> Result<(), crate::Error> {
-        if let Err(err) = self.close() {
+    async fn reconnect(&self) -> Result<(), crate::Error> {
+        if let Err(err) = self.close().await {
             log::error!("Closing datachannel failed with error: {err}");
         }
 
-        self.open()?;
+        self.open().await?;
 
         log::info!(
             "Successfully reconnected to data channel: {}",
@@ -219,23 +342,27 @@ where
         Ok(())
     }
 
-    fn close(&self) -> Result<(), crate::Error> {
+    async fn close(&self) -> Result<(), crate::Error> {
         log::info!(
             "Closing datachannel with url {}",
             self.ws_channel.get_stream_url()
         );
-        self.ws_channel.close()
+        self.ws_channel.close().await
     }
 
-    fn open(&self) -> Result<(), crate::Error> {
-        self.ws_channel.open()?;
+    async fn open(&self) -> Result<(), crate::Error> {
+        self.ws_channel.open().await?;
 
-        self.finalize_data_channel_handshake(self.ws_channel.get_channel_token())?;
+        self.finalize_data_channel_handshake(self.ws_channel.get_channel_token())
+            .await?;
 
         Ok(())
     }
 
-    fn finalize_data_channel_handshake(&self, channel_token: &str) -> Result<(), crate::Error> {
+    async fn finalize_data_channel_handshake(
+        &self,
+        channel_token: &str,
+    ) -> Result<(), crate::Error> {
         log::info!(
             "Sending token through data channel {} to acknowledge connection",
             self.ws_channel.get_stream_url()
@@ -247,21 +374,21 @@ where
         let open_data_channel_input = serde_json::to_string(&open_data_channel_input)
             .map_err(crate::Error::OpenDataChannelInputSerialization)?;
 
-        // TODO: need to check tokio-tungstenite and handle this the way it should be handled
-        self.send_message(open_data_channel_input.as_bytes(), 0)
+        self.send_message(open_data_channel_input.as_bytes(), TEXT_MESSAGE)
+            .await
     }
 
-    fn send_message(&self, input: &[u8], input_type: u32) -> Result<(), crate::Error> {
+    async fn send_message(&self, input: &[u8], input_type: u32) -> Result<(), crate::Error> {
         // Original code included a test hook like this, in a go-specific way. This is an attempt to reproduce the pattern
         // in Rust without affecting runtime performance.
         #[cfg(test)]
         if let Some(hook) = &self.send_message_test_hook {
             hook(input, input_type);
         }
-        self.ws_channel.send_message(input, input_type)
+        self.ws_channel.send_message(input, input_type).await
     }
 
-    fn send_input_data_message(
+    async fn send_input_data_message(
         &self,
         payload_type: message::PayloadType,
         input_data: &[u8],
@@ -274,50 +401,56 @@ where
             input_data
         };
 
-        if self.encryption_enabled && payload_type == message::PayloadType::Output {
-            todo!()
-        }
-
-        let client_message = message::ClientMessage::new(
-            MessageType::InputStreamMessage,
-            message::Flags::empty(),
-            payload_type,
-            input_data.to_vec(), // TODO: remove allocations by using a slice or array instead of a vector
-            (*self.stream_data_sequence_number.borrow()).into(), // TODO: understand why message uses a i64 and not a u32
-        )
-        .map_err(crate::Error::InvalidClientMessage)?;
-
-        log::trace!(
-            "Sending message with seq number: {}",
-            self.stream_data_sequence_number.borrow()
-        );
+        let input_data = match &*lock(&self.encrypter) {
+            Some(encrypter) if payload_type == message::PayloadType::Output => encrypter
+                .encrypt(input_data)
+                .map_err(crate::Error::Encryption)?,
+            _ => input_data.to_vec(), // TODO: remove allocations by using a slice or array instead of a vector
+        };
 
-        // TODO: need to make an error log message here to match the original implementation
-        let msg = client_message.serialize()?;
+        // The sequence number is reserved and the message buffered under one lock, before the send is
+        // awaited, so that concurrent callers never reuse a sequence number. A message which fails to send
+        // stays buffered and is resent like any other unacknowledged message.
+        let (sequence_number, msg) = {
+            let mut next_sequence_number = lock(&self.stream_data_sequence_number);
+            let sequence_number = *next_sequence_number;
+
+            let client_message = message::ClientMessage::builder()
+                .with_sequence_number(sequence_number.into()) // TODO: understand why message uses a i64 and not a u32
+                .input_stream_data(payload_type, input_data)
+                .map_err(crate::Error::InvalidClientMessage)?
+                .build();
+
+            // TODO: need to make an error log message here to match the original implementation
+            let msg = client_message.serialize()?;
+
+            self.add_data_to_outgoing_message_buffer(StreamingMessage::new(
+                msg.clone(),
+                sequence_number.into(),
+            ));
+            *next_sequence_number += 1;
+
+            (sequence_number, msg)
+        };
 
-        // TODO: log an error message if error as with original
-        self.send_message(&msg, 0)?;
+        log::trace!("Sending message with seq number: {sequence_number}");
 
-        let streaming_message =
-            StreamingMessage::new(msg, (*self.stream_data_sequence_number.borrow()).into());
+        self.send_message(&msg, BINARY_MESSAGE)
+            .await
+            .inspect_err(|e| {
+                log::warn!("Failed to send message with seq number {sequence_number}, it will be resent: {e}");
+            })
+    }
 
-        self.add_data_to_outgoing_message_buffer(streaming_message);
+    async fn send_flag(&self, flag: message::PayloadTypeFlag) -> Result<(), crate::Error> {
+        log::debug!("Sending flag: {flag:?}");
 
-        self.stream_data_sequence_number.replace_with(|x| *x + 1);
-        dbg!(&self.stream_data_sequence_number);
-        Ok(())
+        self.send_input_data_message(PayloadType::Flag, &flag.encode())
+            .await
     }
 
     fn add_data_to_outgoing_message_buffer(&self, stream_message: StreamingMessage) {
-        let mut messages = match self.outgoing_message_buffer.lock() {
-            Ok(a) => a,
-            Err(e) => {
-                log::error!(
-                    "Thread panicked while holding a Mutex lock. Please report to the crate's maintainers: {e}"
-                );
-                e.into_inner()
-            }
-        };
+        let mut messages = lock(&self.outgoing_message_buffer);
 
         if messages.is_full() {
             let message = messages.pop_front();
@@ -329,9 +462,539 @@ where
 
     fn remove_data_from_outgoing_message_buffer(
         &self,
-        _streaming_message: Option<&StreamingMessage>,
-    ) {
-        todo!()
+        sequence_number: u64,
+    ) -> Option<StreamingMessage> {
+        lock(&self.outgoing_message_buffer).remove(sequence_number)
+    }
+
+    fn process_acknowledged_message(&self, acknowledge_content: &message::AcknowledgeContent) {
+        let Ok(sequence_number) = u64::try_from(acknowledge_content.sequence_number()) else {
+            log::warn!(
+                "Ignoring acknowledgement with invalid seq number: {}",
+                acknowledge_content.sequence_number()
+            );
+            return;
+        };
+
+        let Some(streaming_message) =
+            self.remove_data_from_outgoing_message_buffer(sequence_number)
+        else {
+            return;
+        };
+
+        // The acknowledgement of a resent message may answer any of its copies, so it does not measure
+        // the round trip time (Karn's algorithm).
+        if streaming_message.resent_attempt == 0 {
+            lock(&self.round_trip_times).update(streaming_message.last_sent_time.elapsed());
+        }
+    }
+
+    async fn resend_stream_data_message_scheduler(&self) -> Result<Infallible, crate::Error> {
+        loop {
+            tokio::time::sleep(Duration::from_millis(config::RESEND_SLEEP_INTERVAL_MILLIS)).await;
+
+            for content in self.take_messages_to_resend()? {
+                if let Err(e) = self.send_message(&content, BINARY_MESSAGE).await {
+                    log::error!("Unable to resend stream data message: {e}");
+                }
+            }
+        }
+    }
+
+    async fn output_message_handler(
+        &self,
+        raw_message: &[u8],
+    ) -> Result<Vec<DataChannelEvent>, crate::Error> {
+        let message = ClientMessageRef::deserialize(raw_message)
+            .map_err(crate::Error::MessageDeserialization)?;
+
+        match message.message_type() {
+            MessageType::OutputStreamMessage => {
+                self.handle_output_message(&message, raw_message).await
+            }
+            MessageType::AcknowledgeMessage => {
+                let acknowledge_content = message
+                    .deserialize_data_stream_acknowledge_content()
+                    .map_err(crate::Error::InvalidClientMessage)?;
+
+                log::trace!(
+                    "Received acknowledge message for seq number: {}",
+                    acknowledge_content.sequence_number()
+                );
+                self.process_acknowledged_message(&acknowledge_content);
+
+                Ok(Vec::new())
+            }
+            MessageType::StartPublicationMessage | MessageType::PausePublicationMessage => {
+                log::debug!("Received {} message", message.message_type());
+                Ok(Vec::new())
+            }
+            MessageType::ChannelClosedMessage => {
+                let channel_closed = message
+                    .deserialize_channel_closed_message()
+                    .map_err(crate::Error::InvalidClientMessage)?;
+
+                log::info!(
+                    "Exiting session with sessionId: {} with output: {}",
+                    self.session_id,
+                    channel_closed.output
+                );
+
+                Ok(vec![DataChannelEvent::ChannelClosed(channel_closed)])
+            }
+            message_type @ MessageType::InputStreamMessage => {
+                log::warn!("Invalid message type received: {message_type}");
+                Ok(Vec::new())
+            }
+            MessageType::Unknown(message_type) => {
+                // A newer agent may send message types this client does not understand. Acknowledge them so
+                // the agent does not resend them, and carry on with the session.
+                log::warn!(
+                    "Ignoring message with unknown message type {message_type} and seq number: {}",
+                    message.sequence_number()
+                );
+                self.send_acknowledge_message(&message).await?;
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    async fn receive_events(&self) -> Result<Vec<DataChannelEvent>, crate::Error> {
+        let mut incoming = self.incoming.lock().await;
+
+        loop {
+            // The websocket channel only closes the receiver when another subscriber replaces it.
+            match incoming.recv().await {
+                Some(Ok(message)) => return self.output_message_handler(&message).await,
+                Some(Err(crate::Error::WebsocketPongTimeout(timeout))) => {
+                    log::warn!(
+                        "No pong received within {timeout:?}. Reconnecting data channel {}",
+                        self.ws_channel.get_stream_url()
+                    );
+                    self.reconnect()
+                        .await
+                        .map_err(|e| crate::Error::Reconnect {
+                            source: Box::new(e),
+                            stream_url: self.ws_channel.get_stream_url().to_string(),
+                        })?;
+                }
+                Some(Err(e)) => return Err(e),
+                None => return Err(crate::Error::WebsocketClosed),
+            }
+        }
+    }
+}
+
+impl<Channel> DefaultDataChannel<Channel>
+where
+    Channel: WebsocketChannel,
+{
+    /// Set the KMS client used to generate the data key when the agent requests KMS encryption. Without
+    /// one, sessions that require encryption fail during the handshake.
+    pub fn set_kms_client(&mut self, kms_client: impl KmsClient + 'static) {
+        self.kms_client = Some(Box::new(kms_client));
+    }
+
+    /// Process and acknowledge an `output_stream_data` message. Ported from `HandleOutputMessage` in the
+    /// original implementation.
+    ///
+    /// An in-order message is processed before it is acknowledged. The expected sequence number advances
+    /// and the message is acknowledged even if processing fails, so that the agent does not resend a
+    /// message which can never be processed and stall the stream behind it. The failure is logged, and
+    /// the messages buffered behind it are still delivered.
+    async fn handle_output_message(
+        &self,
+        message: &ClientMessageRef<'_>,
+        raw_message: &[u8],
+    ) -> Result<Vec<DataChannelEvent>, crate::Error> {
+        let expected_sequence_number = i64::from(*lock(&self.expected_sequence_number));
+        let sequence_number = message.sequence_number();
+
+        if sequence_number == expected_sequence_number {
+            let processed = self.process_stream_data(message).await;
+            *lock(&self.expected_sequence_number) += 1;
+
+            // A lost acknowledgement only makes the agent resend the message, which is then acknowledged
+            // as a duplicate, so the processed data is not discarded if it cannot be sent.
+            if let Err(e) = self.send_acknowledge_message(message).await {
+                log::warn!("Failed to acknowledge message with seq number {sequence_number}: {e}");
+            }
+
+            let mut events = Vec::new();
+            match processed {
+                Ok(event) => events.extend(event),
+                Err(e) => log::error!(
+                    "Failed to process stream data message with seq number {sequence_number}: {e}"
+                ),
+            }
+            self.process_incoming_message_buffer_items(&mut events)
+                .await;
+
+            return Ok(events);
+        }
+
+        log::debug!(
+            "Unexpected sequence message received. Received Sequence Number: {sequence_number}. Expected Sequence Number: {expected_sequence_number}"
+        );
+
+        if sequence_number > expected_sequence_number {
+            if lock(&self.incoming_message_buffer).is_full() {
+                log::warn!(
+                    "Incoming message buffer full. Dropping message with seq number: {sequence_number}"
+                );
+            } else {
+                self.send_acknowledge_message(message).await?;
+                lock(&self.incoming_message_buffer).insert(StreamingMessage::new(
+                    raw_message.to_vec(),
+                    sequence_number.cast_unsigned(),
+                ));
+            }
+        } else {
+            // The message was already processed, so our acknowledgement was probably lost. Acknowledge it
+            // again so the agent stops resending it.
+            self.send_acknowledge_message(message).await?;
+        }
+
+        Ok(Vec::new())
+    }
+
+    /// Process buffered messages for as long as the buffer contains the next expected sequence number.
+    /// Buffered messages have already been acknowledged and will not be resent, so the expected sequence
+    /// number advances past a message even if processing it fails, and the failure is only logged.
+    async fn process_incoming_message_buffer_items(&self, events: &mut Vec<DataChannelEvent>) {
+        loop {
+            let expected_sequence_number = *lock(&self.expected_sequence_number);
+
+            let Some(buffered_message) =
+                lock(&self.incoming_message_buffer).remove(expected_sequence_number)
+            else {
+                return;
+            };
+
+            log::debug!(
+                "Process stream data message from IncomingMessageBuffer. Sequence Number: {}",
+                buffered_message.sequence_number
+            );
+
+            *lock(&self.expected_sequence_number) += 1;
+
+            let processed = match ClientMessageRef::deserialize(&buffered_message.content) {
+                Ok(message) => self.process_stream_data(&message).await,
+                Err(e) => Err(crate::Error::MessageDeserialization(e)),
+            };
+
+            match processed {
+                Ok(event) => events.extend(event),
+                Err(e) => log::error!(
+                    "Failed to process buffered stream data message with seq number {}: {e}",
+                    buffered_message.sequence_number
+                ),
+            }
+        }
+    }
+
+    /// Process an in-order stream data message. Handshake messages are answered here and do not produce
+    /// an event until the handshake is complete.
+    async fn process_stream_data(
+        &self,
+        message: &ClientMessageRef<'_>,
+    ) -> Result<Option<DataChannelEvent>, crate::Error> {
+        log::trace!(
+            "Process new incoming stream data message. Sequence Number: {}",
+            message.sequence_number()
+        );
+
+        match message.payload_type() {
+            PayloadType::HandshakeRequestPayloadType => {
+                let request = message
+                    .deserialize_handshake_request()
+                    .map_err(crate::Error::InvalidClientMessage)?;
+                self.handle_handshake_request(request).await?;
+
+                Ok(None)
+            }
+            PayloadType::HandshakeCompletePayloadType => {
+                let complete = message
+                    .deserialize_handshake_complete()
+                    .map_err(crate::Error::InvalidClientMessage)?;
+
+                log::debug!(
+                    "Handshake Complete. Handshake time to complete is: {} seconds",
+                    complete.handshake_time_to_complete.as_secs_f64()
+                );
+
+                Ok(Some(DataChannelEvent::HandshakeComplete {
+                    session_type: lock(&self.session_type).clone(),
+                    customer_message: complete.customer_message,
+                }))
+            }
+            PayloadType::EncChallengeRequest => {
+                let request = message
+                    .deserialize_encryption_challenge_request()
+                    .map_err(crate::Error::InvalidClientMessage)?;
+                self.handle_encryption_challenge_request(&request.challenge)
+                    .await?;
+
+                Ok(None)
+            }
+            PayloadType::Flag => {
+                let flag = message::PayloadTypeFlag::decode(message.payload())
+                    .map_err(crate::Error::MessageDeserialization)?;
+
+                log::debug!("Received flag: {flag:?}");
+
+                Ok(Some(DataChannelEvent::Flag(flag)))
+            }
+            payload_type @ (PayloadType::Output | PayloadType::StdErr | PayloadType::ExitCode) => {
+                let payload = match &*lock(&self.encrypter) {
+                    Some(encrypter) => encrypter
+                        .decrypt(message.payload())
+                        .map_err(crate::Error::Encryption)?,
+                    None => message.payload().to_vec(),
+                };
+
+                if payload_type == PayloadType::ExitCode {
+                    let exit_code = message::parse_exit_code(&payload)
+                        .map_err(crate::Error::MessageDeserialization)?;
+
+                    log::debug!("Received exit code: {exit_code}");
+
+                    return Ok(Some(DataChannelEvent::ExitCode(exit_code)));
+                }
+
+                Ok(Some(DataChannelEvent::StreamData {
+                    payload_type,
+                    payload,
+                }))
+            }
+            PayloadType::Unknown(payload_type) => {
+                log::warn!(
+                    "Ignoring stream data with unknown payload type {payload_type} and seq number: {}",
+                    message.sequence_number()
+                );
+                Ok(None)
+            }
+            payload_type => Ok(Some(DataChannelEvent::StreamData {
+                payload_type,
+                payload: message.payload().to_vec(),
+            })),
+        }
+    }
+
+    /// Prove to the agent that both ends derived the same session keys by decrypting the challenge and
+    /// encrypting it again. Ported from `handleEncryptionChallengeRequest` in the original implementation.
+    async fn handle_encryption_challenge_request(
+        &self,
+        challenge: &[u8],
+    ) -> Result<(), crate::Error> {
+        let response = {
+            let encrypter = lock(&self.encrypter);
+            let encrypter = encrypter
+                .as_ref()
+                .ok_or(crate::Error::EncryptionNotEnabled)?;
+            let challenge = encrypter
+                .decrypt(challenge)
+                .and_then(|challenge| encrypter.encrypt(&challenge))
+                .map_err(crate::Error::Encryption)?;
+
+            EncryptionChallengeResponse { challenge }
+        };
+
+        log::debug!("Sending encryption challenge response");
+
+        let payload = serde_json::to_vec(&response)
+            .map_err(|e| crate::Error::InvalidClientMessage(e.into()))?;
+
+        self.send_input_data_message(PayloadType::EncChallengeResponse, &payload)
+            .await
+    }
+
+    /// Process the actions requested by the agent and send the handshake response. Ported from
+    /// `handleHandshakeRequest` in the original implementation.
+    async fn handle_handshake_request(
+        &self,
+        request: HandshakeRequestPayload,
+    ) -> Result<(), crate::Error> {
+        log::debug!(
+            "Received handshake request from agent version: {}",
+            request.agent_version
+        );
+        *lock(&self.agent_version) = request.agent_version;
+
+        let mut processed_client_actions = Vec::new();
+        for action in request.requested_client_actions {
+            let result = match action.action_type {
+                ActionType::SessionType => self
+                    .process_session_type_handshake_action(action.action_parameters)
+                    .map(|()| serde_json::Value::Null),
+                ActionType::KmsEncryption => {
+                    self.process_kms_encryption_handshake_action(action.action_parameters)
+                        .await
+                }
+                ActionType::Unknown(_) => {
+                    processed_client_actions.push(ProcessedClientAction {
+                        error: format!("Unsupported action {}", action.action_type),
+                        action_type: action.action_type,
+                        action_status: ActionStatus::Unsupported,
+                        action_result: serde_json::Value::Null,
+                    });
+                    continue;
+                }
+            };
+
+            processed_client_actions.push(match result {
+                Ok(action_result) => ProcessedClientAction {
+                    action_type: action.action_type,
+                    action_status: ActionStatus::Success,
+                    action_result,
+                    error: String::new(),
+                },
+                Err(e) => ProcessedClientAction {
+                    error: format!("Failed to process action {}: {e}", action.action_type),
+                    action_type: action.action_type,
+                    action_status: ActionStatus::Failed,
+                    action_result: serde_json::Value::Null,
+                },
+            });
+        }
+
+        let response = HandshakeResponsePayload {
+            client_version: config::CLIENT_VERSION.to_string(),
+            errors: processed_client_actions
+                .iter()
+                .filter(|action| !action.error.is_empty())
+                .map(|action| action.error.clone())
+                .collect(),
+            processed_client_actions,
+        };
+
+        self.send_handshake_response(&response).await
+    }
+
+    /// Generate a data key for the session and enable encryption. Returns the action result, which shares
+    /// the encrypted data key with the agent. Ported from `ProcessKMSEncryptionHandshakeAction` in the
+    /// original implementation.
+    async fn process_kms_encryption_handshake_action(
+        &self,
+        action_parameters: serde_json::Value,
+    ) -> Result<serde_json::Value, crate::Error> {
+        if self.is_aws_cli_upgrade_needed {
+            Err(crate::Error::AwsCliUpgradeNeeded)?;
+        }
+
+        let kms_client = self
+            .kms_client
+            .as_ref()
+            .ok_or(crate::Error::MissingKmsClient)?;
+        let request: KmsEncryptionRequest = serde_json::from_value(action_parameters)
+            .map_err(|e| crate::Error::InvalidClientMessage(e.into()))?;
+        let encryption_context = HashMap::from([
+            ("aws:ssm:SessionId".to_string(), self.session_id.clone()),
+            ("aws:ssm:TargetId".to_string(), self.instance_id.clone()),
+        ]);
+
+        let encrypter = kms_client
+            .generate_data_key(&request.kms_key_id, &encryption_context)
+            .await
+            .and_then(Encrypter::new)
+            .map_err(crate::Error::Encryption)?;
+        let response = KmsEncryptionResponse {
+            kms_cipher_text_key: encrypter.encrypted_data_key().to_vec(),
+            kms_cipher_text_hash: Vec::new(),
+        };
+
+        *lock(&self.encrypter) = Some(encrypter);
+
+        serde_json::to_value(response).map_err(|e| crate::Error::InvalidClientMessage(e.into()))
+    }
+
+    /// Record the session type requested by the agent. Shell and command sessions are all handled as shell
+    /// sessions. Ported from `ProcessSessionTypeHandshakeAction` in the original implementation.
+    fn process_session_type_handshake_action(
+        &self,
+        action_parameters: serde_json::Value,
+    ) -> Result<(), crate::Error> {
+        let mut request: SessionTypeRequest = serde_json::from_value(action_parameters)
+            .map_err(|e| crate::Error::InvalidClientMessage(e.into()))?;
+
+        match request.session_type.as_str() {
+            config::SHELL_PLUGIN_NAME
+            | config::INTERACTIVE_COMMANDS_PLUGIN_NAME
+            | config::NON_INTERACTIVE_COMMANDS_PLUGIN_NAME => {
+                request.session_type = config::SHELL_PLUGIN_NAME.to_string();
+            }
+            config::PORT_PLUGIN_NAME => {}
+            session_type => Err(crate::Error::UnknownSessionType(session_type.to_string()))?,
+        }
+
+        *lock(&self.session_type) = Some(request);
+
+        Ok(())
+    }
+
+    async fn send_handshake_response(
+        &self,
+        response: &HandshakeResponsePayload,
+    ) -> Result<(), crate::Error> {
+        log::debug!("Sending handshake response: {response:?}");
+
+        let payload = serde_json::to_vec(response)
+            .map_err(|e| crate::Error::InvalidClientMessage(e.into()))?;
+
+        self.send_input_data_message(PayloadType::HandshakeResponsePayloadType, &payload)
+            .await
+    }
+
+    /// Collect the content of the buffered messages which are due to be resent, and mark them as sent. The
+    /// buffer stays locked only while it is scanned, not while the messages are sent.
+    fn take_messages_to_resend(&self) -> Result<Vec<Vec<u8>>, crate::Error> {
+        let retransmission_timeout = lock(&self.round_trip_times).retransmission_timeout;
+        let now = Instant::now();
+
+        lock(&self.outgoing_message_buffer)
+            .messages
+            .iter_mut()
+            .filter(|message| now.duration_since(message.last_sent_time) > retransmission_timeout)
+            .map(|message| {
+                if message.resent_attempt >= config::RESEND_MAX_ATTEMPT {
+                    log::warn!(
+                        "Message {} was resent over {} times.",
+                        message.sequence_number,
+                        config::RESEND_MAX_ATTEMPT
+                    );
+                    Err(crate::Error::StreamMessageResendTimeout(
+                        message.sequence_number,
+                    ))?;
+                }
+
+                message.resent_attempt += 1;
+                message.last_sent_time = now;
+                log::debug!(
+                    "Resend stream data message {} for the {} attempt.",
+                    message.sequence_number,
+                    message.resent_attempt
+                );
+
+                Ok(message.content.clone())
+            })
+            .collect()
+    }
+
+    /// Send an acknowledgement for a received stream data message.
+    async fn send_acknowledge_message(
+        &self,
+        message: &ClientMessageRef<'_>,
+    ) -> Result<(), crate::Error> {
+        let ack = ClientMessage::acknowledge(message)
+            .map_err(crate::Error::InvalidClientMessage)?
+            .serialize()?;
+
+        log::trace!(
+            "Sending acknowledge message for seq number: {}",
+            message.sequence_number()
+        );
+
+        self.send_message(&ack, BINARY_MESSAGE).await
     }
 }
 
@@ -370,26 +1033,110 @@ impl ListMessageBuffer {
     pub fn push_back(&mut self, message: StreamingMessage) {
         self.messages.push_back(message);
     }
+
+    /// Remove the message with `sequence_number`. Messages are buffered in the order they are sent, and
+    /// usually acknowledged in that order too, so the search starts at the front.
+    pub fn remove(&mut self, sequence_number: u64) -> Option<StreamingMessage> {
+        let index = self
+            .messages
+            .iter()
+            .position(|message| message.sequence_number == sequence_number)?;
+
+        self.messages.remove(index)
+    }
+}
+
+/// The round trip time estimate, and the retransmission timeout derived from it, following
+/// [RFC 6298](https://www.rfc-editor.org/rfc/rfc6298). Corresponds to the `RoundTripTime`,
+/// `RoundTripTimeVariation` and `RetransmissionTimeout` fields of the data channel in the original
+/// implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RoundTripTimes {
+    round_trip_time: Duration,
+    round_trip_time_variation: Duration,
+    retransmission_timeout: Duration,
+    /// Whether a measurement has been made. Until then the defaults from [`config`] are used.
+    measured: bool,
+}
+
+impl Default for RoundTripTimes {
+    fn default() -> Self {
+        Self {
+            round_trip_time: Duration::from_millis(config::DEFAULT_ROUND_TRIP_TIME_MILLIS),
+            round_trip_time_variation: Duration::from_millis(
+                config::DEFAULT_ROUND_TRIP_TIME_VARIATION_MILLIS,
+            ),
+            retransmission_timeout: Duration::from_millis(
+                config::DEFAULT_TRANSMISSION_TIMEOUT_MILLIS,
+            ),
+            measured: false,
+        }
+    }
+}
+
+impl RoundTripTimes {
+    /// Fold a new round trip time measurement into the estimate. Later measurements are smoothed as in
+    /// `CalculateRetransmissionTimeout` in the original implementation, while the first one replaces the
+    /// defaults, as in section 2.2 of the RFC.
+    fn update(&mut self, round_trip_time: Duration) {
+        if self.measured {
+            self.round_trip_time_variation = self
+                .round_trip_time_variation
+                .mul_f64(1.0 - config::RTTV_CONSTANT)
+                + self
+                    .round_trip_time
+                    .abs_diff(round_trip_time)
+                    .mul_f64(config::RTTV_CONSTANT);
+            self.round_trip_time = self.round_trip_time.mul_f64(1.0 - config::RTT_CONSTANT)
+                + round_trip_time.mul_f64(config::RTT_CONSTANT);
+        } else {
+            self.round_trip_time = round_trip_time;
+            self.round_trip_time_variation = round_trip_time / 2;
+            self.measured = true;
+        }
+
+        self.retransmission_timeout = clamp_retransmission_timeout(
+            self.round_trip_time
+                + Duration::from_millis(config::CLOCK_GRANULARITY_MILLIS)
+                    .max(self.round_trip_time_variation * 4),
+        );
+    }
+}
+
+fn clamp_retransmission_timeout(retransmission_timeout: Duration) -> Duration {
+    retransmission_timeout.clamp(
+        Duration::from_millis(config::MIN_TRANSMISSION_TIMEOUT_MILLIS),
+        Duration::from_millis(config::MAX_TRANSMISSION_TIMEOUT_MILLIS),
+    )
 }
 
 #[derive(Debug, Default)]
-#[allow(dead_code)] // TODO: remove after implementation complete
 struct MapMessageBuffer {
-    messages: HashMap<u32, StreamingMessage>, // Wrap in mutex when it becomes necessary
+    messages: HashMap<u64, StreamingMessage>,
 }
 
-#[allow(dead_code)] // TODO: remove after implementation complete
 impl MapMessageBuffer {
     fn new() -> Self {
         Self {
             messages: HashMap::with_capacity(config::INCOMING_MESSAGE_BUFFER_CAPACITY),
         }
     }
+
+    fn is_full(&self) -> bool {
+        self.messages.len() >= config::INCOMING_MESSAGE_BUFFER_CAPACITY
+    }
+
+    pub fn insert(&mut self, message: StreamingMessage) {
+        self.messages.insert(message.sequence_number, message);
+    }
+
+    pub fn remove(&mut self, sequence_number: u32) -> Option<StreamingMessage> {
+        self.messages.remove(&u64::from(sequence_number))
+    }
 }
 
 /// TODO: document
 #[derive(Debug, Clone)]
-#[allow(dead_code)] // TODO: remove after implementation complete
 pub struct StreamingMessage {
     content: Vec<u8>, // TODO: check characterics of message to determine whether a vec or array is more appropriate
     sequence_number: u64,
@@ -424,12 +1171,25 @@ impl Default for StreamingMessage {
 #[cfg(test)]
 mod test {
     use super::DataChannel;
+    use super::DataChannelEvent;
     use super::DefaultDataChannel;
-    use super::config;
-    use crate::message::PayloadType;
+    use super::{RoundTripTimes, StreamingMessage, config};
+    use crate::encryption::FakeKmsClient;
+    use crate::message::{
+        ActionStatus, ActionType, ChannelClosed, ClientMessage, EncryptionChallengeRequest,
+        EncryptionChallengeResponse, HandshakeResponsePayload, KmsEncryptionResponse, MessageType,
+        PayloadType, PayloadTypeFlag, SessionTypeRequest,
+    };
     use crate::service::OpenDataChannelInput;
-    use crate::websocket_channel::MockWebsocketChannel;
+    use crate::websocket_channel::{BINARY_MESSAGE, MockWebsocketChannel, TEXT_MESSAGE};
     use mockall::predicate::eq;
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
 
     const CLIENT_ID: &str = "client-id";
     const SESSION_ID: &str = "session-id";
@@ -440,11 +1200,15 @@ mod test {
     const PAYLOAD: &[u8] = b"testPayload";
     // const STREAM_URL: &str = "stream-url";
 
-    pub type TestHook = Box<dyn Fn(&[u8], u32)>;
+    pub type TestHook = Box<dyn Fn(&[u8], u32) + Send + Sync>;
 
     #[test]
     fn initialize() {
-        let mock_ws_channel = MockWebsocketChannel::new();
+        let mut mock_ws_channel = MockWebsocketChannel::new();
+        mock_ws_channel
+            .expect_subscribe()
+            .once()
+            .returning(|| mpsc::unbounded_channel().1);
 
         let data_channel = DefaultDataChannel::new(
             CLIENT_ID.to_owned(),
@@ -458,19 +1222,20 @@ mod test {
         assert_eq!(SESSION_ID, data_channel.session_id);
         assert_eq!(INSTANCE_ID, data_channel.instance_id);
         assert!(!data_channel.is_aws_cli_upgrade_needed);
-        assert_eq!(0, data_channel.expected_sequence_number);
-        assert_eq!(0, *data_channel.stream_data_sequence_number.borrow());
+        assert_eq!(0, *data_channel.expected_sequence_number.lock().unwrap());
+        assert_eq!(0, *data_channel.stream_data_sequence_number.lock().unwrap());
+        let round_trip_times = *data_channel.round_trip_times.lock().unwrap();
         assert_eq!(
             u128::from(config::DEFAULT_ROUND_TRIP_TIME_MILLIS),
-            data_channel.round_trip_time.as_millis()
+            round_trip_times.round_trip_time.as_millis()
         );
         assert_eq!(
             u128::from(config::DEFAULT_ROUND_TRIP_TIME_VARIATION_MILLIS),
-            data_channel.round_trip_time_variation.as_millis()
+            round_trip_times.round_trip_time_variation.as_millis()
         );
         assert_eq!(
             u128::from(config::DEFAULT_TRANSMISSION_TIMEOUT_MILLIS),
-            data_channel.retransmission_timeout.as_millis()
+            round_trip_times.retransmission_timeout.as_millis()
         );
     }
 
@@ -479,12 +1244,18 @@ mod test {
     // #[test]
     // fn set_websocket() {}
 
-    #[test]
-    fn reconnect() {
+    #[tokio::test]
+    async fn reconnect() {
         let mut ws_channel = MockWebsocketChannel::new();
 
-        ws_channel.expect_close().once().returning(|| Ok(()));
-        ws_channel.expect_open().once().returning(|| Ok(()));
+        ws_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        ws_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
 
         ws_channel
             .expect_get_channel_token()
@@ -495,18 +1266,24 @@ mod test {
             .expect_send_message()
             .once()
             .withf(open_data_channel_input)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _| Box::pin(async { Ok(()) }));
 
         let data_channel: DefaultDataChannel<MockWebsocketChannel> = get_data_channel(ws_channel);
 
-        data_channel.reconnect().expect("Reconnect should succeed.");
+        data_channel
+            .reconnect()
+            .await
+            .expect("Reconnect should succeed.");
     }
 
-    #[test]
-    fn open() {
+    #[tokio::test]
+    async fn open() {
         let mut ws_channel = MockWebsocketChannel::new();
 
-        ws_channel.expect_open().once().returning(|| Ok(()));
+        ws_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
         ws_channel
             .expect_get_channel_token()
             .once()
@@ -516,33 +1293,36 @@ mod test {
             .expect_send_message()
             .once()
             .withf(open_data_channel_input)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _| Box::pin(async { Ok(()) }));
 
         let data_channel: DefaultDataChannel<MockWebsocketChannel> = get_data_channel(ws_channel);
 
-        data_channel.open().expect("Open should succeed.");
+        data_channel.open().await.expect("Open should succeed.");
     }
 
-    #[test]
-    fn close() {
+    #[tokio::test]
+    async fn close() {
         let mut ws_channel = MockWebsocketChannel::new();
 
-        ws_channel.expect_close().once().returning(|| Ok(()));
+        ws_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
 
         let data_channel: DefaultDataChannel<MockWebsocketChannel> = get_data_channel(ws_channel);
 
-        data_channel.close().expect("Close should succeed.");
+        data_channel.close().await.expect("Close should succeed.");
     }
 
-    #[test]
-    fn finalize_data_channel_handshake() {
+    #[tokio::test]
+    async fn finalize_data_channel_handshake() {
         let mut ws_channel = MockWebsocketChannel::new();
 
         ws_channel
             .expect_send_message()
             .once()
             .withf(open_data_channel_input)
-            .returning(|_, _| Ok(()));
+            .returning(|_, _| Box::pin(async { Ok(()) }));
 
         // The original code was expecting a call to get_channel_token here, but in Rust, the call to log::info!
         // appears to not be invoked in tests.
@@ -551,47 +1331,48 @@ mod test {
 
         data_channel
             .finalize_data_channel_handshake(CHANNEL_TOKEN)
+            .await
             .expect("Finalize data channel handshake should succeed.");
     }
 
-    #[test]
-    fn test_send_message() {
+    #[tokio::test]
+    async fn test_send_message() {
         let mut ws_channel = MockWebsocketChannel::new();
 
         ws_channel
             .expect_send_message()
             .once()
             .with(eq(MESSAGE), eq(0))
-            .returning(|_, _| Ok(()));
+            .returning(|_, _| Box::pin(async { Ok(()) }));
 
         let data_channel: DefaultDataChannel<MockWebsocketChannel> = get_data_channel(ws_channel);
 
         data_channel
             .send_message(MESSAGE, 0)
+            .await
             .expect("Send message should succeed.");
     }
 
-    #[test]
-    fn send_input_data_message() {
+    #[tokio::test]
+    async fn send_input_data_message() {
         let mut ws_channel = MockWebsocketChannel::new();
 
         ws_channel
             .expect_send_message()
             .once()
-            // We don't test the input now since that would require implementing the serializer, which is too much
-            // work for the current unit of work
-            // .with(eq(MESSAGE), eq(0))
-            .returning(|_, _| Ok(()));
+            .withf(|input, _| input.ends_with(PAYLOAD) && input.len() > PAYLOAD.len())
+            .returning(|_, _| Box::pin(async { Ok(()) }));
 
         let data_channel: DefaultDataChannel<MockWebsocketChannel> = get_data_channel(ws_channel);
 
         data_channel
             .send_input_data_message(PayloadType::Output, PAYLOAD)
+            .await
             .expect("Send input data message should succeed.");
 
         assert_eq!(
             STREAM_DATA_SEQUENCE_NUMBER + 1,
-            *data_channel.stream_data_sequence_number.borrow()
+            *data_channel.stream_data_sequence_number.lock().unwrap()
         );
         assert_eq!(
             1,
@@ -604,31 +1385,906 @@ mod test {
         );
     }
 
-    // TODO: finish test
-    // #[test]
-    // fn process_acknowledged_message() {
-    //     let ws_channel = MockWebsocketChannel::new();
-    //     let (_, mut streaming_messages) = get_client_and_streaming_messages_list(1);
-
-    //     let data_channel = get_data_channel(ws_channel);
-    //     data_channel.add_data_to_outgoing_message_buffer(mem::take(&mut streaming_messages[0]));
-
-    //     // TODO: implement type
-    //     // message::AckownedgedContent {}
-
-    //     // TODO: implement function
-    //     // data_channel.process_achnowledged_message()
-
-    //     assert_eq!(
-    //         0,
-    //         data_channel
-    //             .outgoing_message_buffer
-    //             .lock()
-    //             .unwrap()
-    //             .messages
-    //             .len(),
-    //     );
-    // }
+    #[tokio::test]
+    async fn concurrent_input_data_messages_use_distinct_sequence_numbers() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut ws_channel = MockWebsocketChannel::new();
+        let sent_messages = Arc::clone(&sent);
+        ws_channel.expect_send_message().returning(move |input, _| {
+            sent_messages.lock().unwrap().push(input.to_vec());
+            // Suspend every send so that all of the callers are in flight at once.
+            Box::pin(async {
+                tokio::task::yield_now().await;
+                Ok(())
+            })
+        });
+        let data_channel = get_data_channel(ws_channel);
+
+        futures_util::future::try_join_all(
+            (0..5).map(|_| data_channel.send_input_data_message(PayloadType::Output, PAYLOAD)),
+        )
+        .await
+        .expect("Input should be sent.");
+
+        let mut sequence_numbers: Vec<_> = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|input| {
+                ClientMessage::deserialize(input)
+                    .expect("Input should be valid.")
+                    .sequence_number()
+            })
+            .collect();
+        sequence_numbers.sort_unstable();
+        assert_eq!(sequence_numbers, [0, 1, 2, 3, 4]);
+        assert_eq!(5, *data_channel.stream_data_sequence_number.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_input_data_message_is_kept_for_resend() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        ws_channel
+            .expect_send_message()
+            .once()
+            .returning(|_, _| Box::pin(async { Err(crate::Error::WebsocketNotOpen) }));
+        let data_channel = get_data_channel(ws_channel);
+
+        assert!(matches!(
+            data_channel
+                .send_input_data_message(PayloadType::Output, PAYLOAD)
+                .await,
+            Err(crate::Error::WebsocketNotOpen)
+        ));
+
+        assert_eq!(1, *data_channel.stream_data_sequence_number.lock().unwrap());
+        let buffered: Vec<_> = data_channel
+            .outgoing_message_buffer
+            .lock()
+            .unwrap()
+            .messages
+            .iter()
+            .map(|message| message.sequence_number)
+            .collect();
+        assert_eq!(buffered, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_acknowledges_in_order_message() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (message_id, raw_message) = get_output_message(0, PAYLOAD);
+
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, message_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&raw_message)
+            .await
+            .expect("Output message should be processed.");
+
+        assert_eq!(
+            events,
+            vec![DataChannelEvent::StreamData {
+                payload_type: PayloadType::Output,
+                payload: PAYLOAD.to_vec(),
+            }]
+        );
+        assert_eq!(1, *data_channel.expected_sequence_number.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_advances_past_failed_message() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (request_id, request) = get_output_message_with_type(
+            0,
+            PayloadType::HandshakeRequestPayloadType,
+            b"not a handshake request",
+        );
+        let (output_id, output) = get_output_message(1, PAYLOAD);
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, request_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, output_id, 1))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&request)
+            .await
+            .expect("Failure should only be logged.");
+        assert!(events.is_empty());
+        assert_eq!(1, *data_channel.expected_sequence_number.lock().unwrap());
+
+        let events = data_channel
+            .output_message_handler(&output)
+            .await
+            .expect("Next message should be processed.");
+        assert_eq!(
+            events,
+            vec![DataChannelEvent::StreamData {
+                payload_type: PayloadType::Output,
+                payload: PAYLOAD.to_vec(),
+            }]
+        );
+        assert_eq!(2, *data_channel.expected_sequence_number.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_drains_buffer_past_failed_message() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (request_id, request) = get_output_message_with_type(
+            0,
+            PayloadType::HandshakeRequestPayloadType,
+            b"not a handshake request",
+        );
+        let (output_id, output) = get_output_message(1, PAYLOAD);
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, output_id, 1))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, request_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&output)
+            .await
+            .expect("Output message should be buffered.");
+        assert!(events.is_empty());
+
+        let events = data_channel
+            .output_message_handler(&request)
+            .await
+            .expect("Failure should only be logged.");
+        assert_eq!(
+            events,
+            vec![DataChannelEvent::StreamData {
+                payload_type: PayloadType::Output,
+                payload: PAYLOAD.to_vec(),
+            }]
+        );
+        assert_eq!(2, *data_channel.expected_sequence_number.lock().unwrap());
+        assert!(
+            data_channel
+                .incoming_message_buffer
+                .lock()
+                .unwrap()
+                .messages
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_keeps_data_when_acknowledgement_fails() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (_, raw_message) = get_output_message(0, PAYLOAD);
+        ws_channel.expect_send_message().once().returning(|_, _| {
+            Box::pin(async {
+                Err(crate::Error::MessageSerialization(
+                    crate::message::Error::ZeroLengthHeader,
+                ))
+            })
+        });
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&raw_message)
+            .await
+            .expect("Output should be processed.");
+
+        assert_eq!(
+            events,
+            vec![DataChannelEvent::StreamData {
+                payload_type: PayloadType::Output,
+                payload: PAYLOAD.to_vec(),
+            }]
+        );
+        assert_eq!(1, *data_channel.expected_sequence_number.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_buffers_out_of_order_message() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (first_id, first_message) = get_output_message(0, b"first");
+        let (second_id, second_message) = get_output_message(1, b"second");
+
+        let mut sequence = mockall::Sequence::new();
+        ws_channel
+            .expect_send_message()
+            .once()
+            .in_sequence(&mut sequence)
+            .withf(move |input, _| acknowledges(input, second_id, 1))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        ws_channel
+            .expect_send_message()
+            .once()
+            .in_sequence(&mut sequence)
+            .withf(move |input, _| acknowledges(input, first_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&second_message)
+            .await
+            .expect("Output message should be buffered.");
+
+        assert!(events.is_empty());
+        assert_eq!(0, *data_channel.expected_sequence_number.lock().unwrap());
+
+        let events = data_channel
+            .output_message_handler(&first_message)
+            .await
+            .expect("Output messages should be processed.");
+
+        assert_eq!(
+            events,
+            vec![
+                DataChannelEvent::StreamData {
+                    payload_type: PayloadType::Output,
+                    payload: b"first".to_vec(),
+                },
+                DataChannelEvent::StreamData {
+                    payload_type: PayloadType::Output,
+                    payload: b"second".to_vec(),
+                }
+            ]
+        );
+        assert_eq!(2, *data_channel.expected_sequence_number.lock().unwrap());
+        assert!(
+            data_channel
+                .incoming_message_buffer
+                .lock()
+                .unwrap()
+                .messages
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn send_flag() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(|input, _| {
+                let message = ClientMessage::deserialize(input).expect("Message should be valid.");
+
+                message.payload_type() == PayloadType::Flag && message.payload() == [0, 0, 0, 2]
+            })
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        data_channel
+            .send_flag(PayloadTypeFlag::TerminateSession)
+            .await
+            .expect("Flag should be sent.");
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_surfaces_flag() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (message_id, raw_message) = get_output_message_with_type(
+            0,
+            PayloadType::Flag,
+            &PayloadTypeFlag::ConnectToPortError.encode(),
+        );
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, message_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&raw_message)
+            .await
+            .expect("Flag message should be processed.");
+
+        assert_eq!(
+            events,
+            vec![DataChannelEvent::Flag(PayloadTypeFlag::ConnectToPortError)]
+        );
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_surfaces_exit_code() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (message_id, raw_message) =
+            get_output_message_with_type(0, PayloadType::ExitCode, b"42");
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, message_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&raw_message)
+            .await
+            .expect("Exit code message should be processed.");
+
+        assert_eq!(events, vec![DataChannelEvent::ExitCode(42)]);
+    }
+
+    #[tokio::test]
+    async fn receive_events_processes_subscribed_messages() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (incoming, receiver) = mpsc::unbounded_channel();
+        ws_channel
+            .expect_subscribe()
+            .once()
+            .return_once(move || receiver);
+        let (message_id, raw_message) = get_output_message(0, PAYLOAD);
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, message_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = DefaultDataChannel::new(
+            CLIENT_ID.to_string(),
+            SESSION_ID.to_string(),
+            INSTANCE_ID.to_string(),
+            ws_channel,
+        );
+
+        incoming.send(Ok(raw_message.into())).unwrap();
+        incoming.send(Err(crate::Error::WebsocketClosed)).unwrap();
+        drop(incoming);
+
+        assert_eq!(
+            data_channel
+                .receive_events()
+                .await
+                .expect("Message should be processed."),
+            vec![DataChannelEvent::StreamData {
+                payload_type: PayloadType::Output,
+                payload: PAYLOAD.to_vec(),
+            }]
+        );
+        assert!(matches!(
+            data_channel.receive_events().await,
+            Err(crate::Error::WebsocketClosed)
+        ));
+        assert!(matches!(
+            data_channel.receive_events().await,
+            Err(crate::Error::WebsocketClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn receive_events_reconnects_after_pong_timeout() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (incoming, receiver) = mpsc::unbounded_channel();
+        ws_channel
+            .expect_subscribe()
+            .once()
+            .return_once(move || receiver);
+        ws_channel
+            .expect_get_stream_url()
+            .return_const(String::new());
+        ws_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        ws_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        ws_channel
+            .expect_get_channel_token()
+            .once()
+            .return_const(CHANNEL_TOKEN.to_string());
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(|_, message_type| *message_type == TEXT_MESSAGE)
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        let (message_id, raw_message) = get_output_message(0, PAYLOAD);
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, message_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = DefaultDataChannel::new(
+            CLIENT_ID.to_string(),
+            SESSION_ID.to_string(),
+            INSTANCE_ID.to_string(),
+            ws_channel,
+        );
+
+        incoming
+            .send(Err(crate::Error::WebsocketPongTimeout(
+                Duration::from_secs(1),
+            )))
+            .unwrap();
+        incoming.send(Ok(raw_message.into())).unwrap();
+
+        assert_eq!(
+            data_channel
+                .receive_events()
+                .await
+                .expect("Message after the reconnect should be processed."),
+            vec![DataChannelEvent::StreamData {
+                payload_type: PayloadType::Output,
+                payload: PAYLOAD.to_vec(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_acknowledges_unknown_message_type() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (message_id, mut raw_message) = get_output_message(0, PAYLOAD);
+        // The message type is a space padded 32 byte string following the 4 byte header length.
+        raw_message[4..36].copy_from_slice(format!("{:<32}", "interactive_shell_data").as_bytes());
+        let message_type = MessageType::Unknown("interactive_shell_data".to_string());
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges_type(input, &message_type, message_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&raw_message)
+            .await
+            .expect("Unknown message should be ignored.");
+
+        assert!(events.is_empty());
+        assert_eq!(0, *data_channel.expected_sequence_number.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_skips_unknown_payload_type() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (message_id, raw_message) =
+            get_output_message_with_type(0, PayloadType::Unknown(13), PAYLOAD);
+        ws_channel
+            .expect_send_message()
+            .once()
+            .withf(move |input, _| acknowledges(input, message_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&raw_message)
+            .await
+            .expect("Unknown payload type should be skipped.");
+
+        assert!(events.is_empty());
+        assert_eq!(1, *data_channel.expected_sequence_number.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_surfaces_channel_closed() {
+        let ws_channel = MockWebsocketChannel::new();
+        let channel_closed = ChannelClosed {
+            session_id: SESSION_ID.to_string(),
+            output: "Session terminated".to_string(),
+            ..Default::default()
+        };
+        let raw_message = ClientMessage::builder()
+            .channel_closed(&channel_closed)
+            .expect("Content should serialize.")
+            .build()
+            .serialize()
+            .expect("Message should serialize.");
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&raw_message)
+            .await
+            .expect("Channel closed message should be processed.");
+
+        assert_eq!(
+            events,
+            vec![DataChannelEvent::ChannelClosed(channel_closed)]
+        );
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_answers_handshake() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        let (request_id, request) = get_output_message_with_type(
+            0,
+            PayloadType::HandshakeRequestPayloadType,
+            br#"{
+                "AgentVersion": "3.3.40.0",
+                "RequestedClientActions": [
+                    {
+                        "ActionType": "SessionType",
+                        "ActionParameters": { "SessionType": "InteractiveCommands", "Properties": null }
+                    },
+                    { "ActionType": "Teleport", "ActionParameters": null }
+                ]
+            }"#,
+        );
+        let (complete_id, complete) = get_output_message_with_type(
+            1,
+            PayloadType::HandshakeCompletePayloadType,
+            br#"{"HandshakeTimeToComplete": 1000000, "CustomerMessage": "Welcome"}"#,
+        );
+
+        let mut sequence = mockall::Sequence::new();
+        ws_channel
+            .expect_send_message()
+            .once()
+            .in_sequence(&mut sequence)
+            .withf(|input, _| {
+                let Ok(message) = ClientMessage::deserialize(input) else {
+                    return false;
+                };
+                if message.payload_type() != PayloadType::HandshakeResponsePayloadType {
+                    return false;
+                }
+                let response: HandshakeResponsePayload =
+                    serde_json::from_slice(message.payload()).expect("Payload should be JSON.");
+
+                response.processed_client_actions[0].action_status == ActionStatus::Success
+                    && response.processed_client_actions[1].action_type
+                        == ActionType::Unknown("Teleport".to_string())
+                    && response.processed_client_actions[1].action_status
+                        == ActionStatus::Unsupported
+                    && response.errors == ["Unsupported action Teleport"]
+            })
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        ws_channel
+            .expect_send_message()
+            .once()
+            .in_sequence(&mut sequence)
+            .withf(move |input, _| acknowledges(input, request_id, 0))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        ws_channel
+            .expect_send_message()
+            .once()
+            .in_sequence(&mut sequence)
+            .withf(move |input, _| acknowledges(input, complete_id, 1))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let data_channel = get_data_channel(ws_channel);
+
+        let events = data_channel
+            .output_message_handler(&request)
+            .await
+            .expect("Handshake request should be processed.");
+        assert!(events.is_empty());
+        assert_eq!(*data_channel.agent_version.lock().unwrap(), "3.3.40.0");
+
+        let events = data_channel
+            .output_message_handler(&complete)
+            .await
+            .expect("Handshake complete should be processed.");
+        assert_eq!(
+            events,
+            vec![DataChannelEvent::HandshakeComplete {
+                session_type: Some(SessionTypeRequest {
+                    session_type: config::SHELL_PLUGIN_NAME.to_string(),
+                    properties: serde_json::Value::Null,
+                }),
+                customer_message: "Welcome".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn output_message_handler_enables_kms_encryption() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut ws_channel = MockWebsocketChannel::new();
+        let sent_messages = Arc::clone(&sent);
+        ws_channel.expect_send_message().returning(move |input, _| {
+            sent_messages.lock().unwrap().push(input.to_vec());
+            Box::pin(async { Ok(()) })
+        });
+        let kms = Arc::new(FakeKmsClient::new());
+        let mut data_channel = get_data_channel(ws_channel);
+        data_channel.set_kms_client(Arc::clone(&kms));
+
+        let (_, request) = get_output_message_with_type(
+            0,
+            PayloadType::HandshakeRequestPayloadType,
+            br#"{
+                "AgentVersion": "3.3.40.0",
+                "RequestedClientActions": [
+                    { "ActionType": "KMSEncryption", "ActionParameters": { "KMSKeyId": "key-id" } }
+                ]
+            }"#,
+        );
+        data_channel
+            .output_message_handler(&request)
+            .await
+            .expect("Handshake request should be processed.");
+
+        let response = ClientMessage::deserialize(&sent.lock().unwrap()[0])
+            .expect("Response should be valid.");
+        let response: HandshakeResponsePayload =
+            serde_json::from_slice(response.payload()).expect("Payload should be JSON.");
+        let action = &response.processed_client_actions[0];
+        assert_eq!(action.action_status, ActionStatus::Success);
+        let result: KmsEncryptionResponse = serde_json::from_value(action.action_result.clone())
+            .expect("Action result should contain the data key.");
+        let encryption_context = HashMap::from([
+            ("aws:ssm:SessionId".to_string(), SESSION_ID.to_string()),
+            ("aws:ssm:TargetId".to_string(), INSTANCE_ID.to_string()),
+        ]);
+        kms.decrypt(&result.kms_cipher_text_key, &encryption_context)
+            .expect("Agent should be able to decrypt the data key.");
+
+        // Act as the agent from here on, using the same keys in the opposite direction.
+        let agent = data_channel
+            .encrypter
+            .lock()
+            .unwrap()
+            .as_ref()
+            .expect("Encryption should be enabled.")
+            .reversed();
+
+        let challenge = serde_json::to_vec(&EncryptionChallengeRequest {
+            challenge: agent.encrypt(b"challenge").unwrap(),
+        })
+        .unwrap();
+        let (_, challenge) =
+            get_output_message_with_type(1, PayloadType::EncChallengeRequest, &challenge);
+        let events = data_channel
+            .output_message_handler(&challenge)
+            .await
+            .expect("Encryption challenge should be processed.");
+        assert!(events.is_empty());
+
+        let response = ClientMessage::deserialize(&sent.lock().unwrap()[2])
+            .expect("Response should be valid.");
+        assert_eq!(response.payload_type(), PayloadType::EncChallengeResponse);
+        let response: EncryptionChallengeResponse =
+            serde_json::from_slice(response.payload()).expect("Payload should be JSON.");
+        assert_eq!(agent.decrypt(&response.challenge).unwrap(), b"challenge");
+
+        let (_, output) = get_output_message(2, &agent.encrypt(PAYLOAD).unwrap());
+        let events = data_channel
+            .output_message_handler(&output)
+            .await
+            .expect("Encrypted output should be processed.");
+        assert_eq!(
+            events,
+            vec![DataChannelEvent::StreamData {
+                payload_type: PayloadType::Output,
+                payload: PAYLOAD.to_vec(),
+            }]
+        );
+
+        data_channel
+            .send_input_data_message(PayloadType::Output, PAYLOAD)
+            .await
+            .expect("Input should be sent.");
+        let input =
+            ClientMessage::deserialize(&sent.lock().unwrap()[5]).expect("Input should be valid.");
+        assert_eq!(agent.decrypt(input.payload()).unwrap(), PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn kms_encryption_fails_without_kms_client() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut ws_channel = MockWebsocketChannel::new();
+        let sent_messages = Arc::clone(&sent);
+        ws_channel.expect_send_message().returning(move |input, _| {
+            sent_messages.lock().unwrap().push(input.to_vec());
+            Box::pin(async { Ok(()) })
+        });
+        let data_channel = get_data_channel(ws_channel);
+
+        let (_, request) = get_output_message_with_type(
+            0,
+            PayloadType::HandshakeRequestPayloadType,
+            br#"{"RequestedClientActions": [{ "ActionType": "KMSEncryption", "ActionParameters": {} }]}"#,
+        );
+        data_channel
+            .output_message_handler(&request)
+            .await
+            .expect("Handshake request should be processed.");
+
+        let response = ClientMessage::deserialize(&sent.lock().unwrap()[0])
+            .expect("Response should be valid.");
+        let response: HandshakeResponsePayload =
+            serde_json::from_slice(response.payload()).expect("Payload should be JSON.");
+        assert_eq!(
+            response.processed_client_actions[0].action_status,
+            ActionStatus::Failed
+        );
+        assert_eq!(response.errors.len(), 1);
+        assert!(data_channel.encrypter.lock().unwrap().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn process_acknowledged_message() {
+        let data_channel = get_data_channel(MockWebsocketChannel::new());
+        for sequence_number in 0..3 {
+            data_channel.add_data_to_outgoing_message_buffer(StreamingMessage::new(
+                MESSAGE.to_vec(),
+                sequence_number,
+            ));
+        }
+        tokio::time::advance(Duration::from_millis(300)).await;
+
+        let ack = acknowledge_input_stream_message(1);
+        assert_eq!(
+            data_channel
+                .output_message_handler(&ack)
+                .await
+                .expect("Acknowledgement should be processed."),
+            Vec::new()
+        );
+        let buffered: Vec<_> = data_channel
+            .outgoing_message_buffer
+            .lock()
+            .unwrap()
+            .messages
+            .iter()
+            .map(|message| message.sequence_number)
+            .collect();
+        assert_eq!(buffered, vec![0, 2]);
+
+        // The first measurement replaces the defaults.
+        let round_trip_times = *data_channel.round_trip_times.lock().unwrap();
+        assert_eq!(round_trip_times.round_trip_time, Duration::from_millis(300));
+        assert_eq!(
+            round_trip_times.round_trip_time_variation,
+            Duration::from_millis(150)
+        );
+        assert_eq!(
+            round_trip_times.retransmission_timeout,
+            Duration::from_millis(900)
+        );
+
+        // A repeated acknowledgement changes nothing.
+        data_channel.output_message_handler(&ack).await.unwrap();
+        assert_eq!(
+            data_channel
+                .outgoing_message_buffer
+                .lock()
+                .unwrap()
+                .messages
+                .len(),
+            2
+        );
+        assert_eq!(
+            *data_channel.round_trip_times.lock().unwrap(),
+            round_trip_times
+        );
+
+        // Later measurements, here 400ms, are smoothed.
+        tokio::time::advance(Duration::from_millis(100)).await;
+        data_channel
+            .output_message_handler(&acknowledge_input_stream_message(0))
+            .await
+            .unwrap();
+        let round_trip_times = *data_channel.round_trip_times.lock().unwrap();
+        assert_eq!(
+            round_trip_times.round_trip_time,
+            Duration::from_micros(312_500)
+        );
+        assert_eq!(
+            round_trip_times.round_trip_time_variation,
+            Duration::from_micros(137_500)
+        );
+        assert_eq!(
+            round_trip_times.retransmission_timeout,
+            Duration::from_micros(862_500)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acknowledged_resent_message_is_not_measured() {
+        let data_channel = get_data_channel(MockWebsocketChannel::new());
+        data_channel.add_data_to_outgoing_message_buffer(StreamingMessage {
+            resent_attempt: 1,
+            ..StreamingMessage::new(MESSAGE.to_vec(), 1)
+        });
+        tokio::time::advance(Duration::from_millis(300)).await;
+
+        data_channel
+            .output_message_handler(&acknowledge_input_stream_message(1))
+            .await
+            .unwrap();
+
+        assert!(
+            data_channel
+                .outgoing_message_buffer
+                .lock()
+                .unwrap()
+                .messages
+                .is_empty()
+        );
+        assert_eq!(
+            *data_channel.round_trip_times.lock().unwrap(),
+            RoundTripTimes::default()
+        );
+    }
+
+    #[test]
+    fn retransmission_timeout_is_clamped() {
+        let mut round_trip_times = RoundTripTimes::default();
+
+        round_trip_times.update(Duration::from_millis(1));
+        assert_eq!(
+            round_trip_times.retransmission_timeout,
+            Duration::from_millis(config::MIN_TRANSMISSION_TIMEOUT_MILLIS)
+        );
+
+        round_trip_times.update(Duration::from_secs(10));
+        assert_eq!(
+            round_trip_times.retransmission_timeout,
+            Duration::from_millis(config::MAX_TRANSMISSION_TIMEOUT_MILLIS)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resend_stream_data_message_scheduler_resends_unacknowledged_messages() {
+        let mut ws_channel = MockWebsocketChannel::new();
+        ws_channel
+            .expect_send_message()
+            .times(2)
+            .with(eq(MESSAGE), eq(BINARY_MESSAGE))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        let data_channel = get_data_channel(ws_channel);
+        data_channel
+            .add_data_to_outgoing_message_buffer(StreamingMessage::new(MESSAGE.to_vec(), 0));
+
+        // The default 200ms retransmission timeout first expires at the 300ms scan, then again at 600ms.
+        assert!(
+            tokio::time::timeout(
+                Duration::from_millis(650),
+                data_channel.resend_stream_data_message_scheduler(),
+            )
+            .await
+            .is_err()
+        );
+
+        let buffer = data_channel.outgoing_message_buffer.lock().unwrap();
+        assert_eq!(buffer.messages[0].resent_attempt, 2);
+        assert_eq!(
+            buffer.messages[0].last_sent_time.elapsed(),
+            Duration::from_millis(50)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resend_stream_data_message_scheduler_gives_up() {
+        let data_channel = get_data_channel(MockWebsocketChannel::new());
+        data_channel.add_data_to_outgoing_message_buffer(StreamingMessage {
+            resent_attempt: config::RESEND_MAX_ATTEMPT,
+            ..StreamingMessage::new(MESSAGE.to_vec(), 7)
+        });
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert!(matches!(
+            data_channel.resend_stream_data_message_scheduler().await,
+            Err(crate::Error::StreamMessageResendTimeout(7))
+        ));
+    }
 
     // Allow trivially_copy_pass_by_ref because the input is a reference and we can't change that.
     #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -640,12 +2296,84 @@ mod test {
             && input.client_id == CLIENT_ID
             && input.token_value == CHANNEL_TOKEN
             && input.client_version == env!("CARGO_PKG_VERSION")
-            && *message_type == 0 // TODO: check this value
+            && *message_type == TEXT_MESSAGE
+    }
+
+    /// The serialized acknowledgement of the input stream message with `sequence_number`.
+    fn acknowledge_input_stream_message(sequence_number: i64) -> Vec<u8> {
+        let acknowledged = ClientMessage::builder()
+            .with_sequence_number(sequence_number)
+            .input_stream_data(PayloadType::Output, PAYLOAD.to_vec())
+            .expect("Payload should fit.")
+            .build();
+
+        ClientMessage::acknowledge(&(&acknowledged).into())
+            .expect("Acknowledgement should be created.")
+            .serialize()
+            .expect("Acknowledgement should serialize.")
+    }
+
+    fn get_output_message(sequence_number: i64, payload: &[u8]) -> (Uuid, Vec<u8>) {
+        get_output_message_with_type(sequence_number, PayloadType::Output, payload)
+    }
+
+    /// An output stream message as the agent would send it. The payload is not checked against its type,
+    /// so tests can send malformed payloads.
+    fn get_output_message_with_type(
+        sequence_number: i64,
+        payload_type: PayloadType,
+        payload: &[u8],
+    ) -> (Uuid, Vec<u8>) {
+        let message = ClientMessage::builder()
+            .with_sequence_number(sequence_number)
+            .unchecked_output_stream_data(payload_type, payload.to_vec())
+            .expect("Payload should fit.")
+            .build();
+
+        (
+            message.message_id(),
+            message.serialize().expect("Message should serialize."),
+        )
+    }
+
+    /// Mockall evaluates matchers against every call, so this returns false for anything that is not an
+    /// acknowledge message rather than panicking.
+    fn acknowledges(input: &[u8], message_id: Uuid, sequence_number: i64) -> bool {
+        acknowledges_type(
+            input,
+            &MessageType::OutputStreamMessage,
+            message_id,
+            sequence_number,
+        )
+    }
+
+    fn acknowledges_type(
+        input: &[u8],
+        message_type: &MessageType,
+        message_id: Uuid,
+        sequence_number: i64,
+    ) -> bool {
+        let Some(content) = ClientMessage::deserialize(input)
+            .ok()
+            .and_then(|ack| ack.deserialize_data_stream_acknowledge_content().ok())
+        else {
+            return false;
+        };
+
+        content.message_type() == message_type
+            && content.message_id() == message_id
+            && content.sequence_number() == sequence_number
+            && content.is_sequential_message()
     }
 
     fn get_data_channel(
-        ws_channel: MockWebsocketChannel,
+        mut ws_channel: MockWebsocketChannel,
     ) -> DefaultDataChannel<MockWebsocketChannel> {
+        ws_channel
+            .expect_subscribe()
+            .once()
+            .returning(|| mpsc::unbounded_channel().1);
+
         DefaultDataChannel::new(
             CLIENT_ID.to_string(),
             SESSION_ID.to_string(),
@@ -653,10 +2381,4 @@ mod test {
             ws_channel,
         )
     }
-
-    // Could optimize tests by using lazy static to generate a shared list of messages if generating
-    // this dummy data for every test slows the test suite down too much
-    // fn get_client_and_streaming_messages_list(size: u8) -> (Vec<Vec<u8>>, Vec<StreamingMessage>) {
-    //     todo!()
-    // }
 }
@@ -0,0 +1,341 @@
+//! KMS encryption of session data. When the agent requests the `KMSEncryption` handshake action, the
+//! client asks KMS for a data key, shares the encrypted copy of the key with the agent, and uses the
+//! plaintext copy to encrypt and decrypt stream data with AES-GCM.
+//!
+//! Roughly corresponds to the code in [this folder](https://github.com/aws/session-manager-plugin/tree/mainline/src/encryption).
+
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
+use futures_util::future::BoxFuture;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    sync::{Mutex, PoisonError},
+};
+
+/// The size of the nonce prepended to every encrypted payload.
+const NONCE_SIZE: usize = 12;
+
+/// The number of bytes of key material requested from KMS. The first half is used to decrypt data from
+/// the agent and the second half to encrypt data sent to the agent.
+pub const DATA_KEY_SIZE: usize = 64;
+
+/// A client for the subset of the KMS API needed to encrypt a session. Implement this on top of the AWS
+/// SDK, or use [`FakeKmsClient`] in tests.
+#[mockall::automock]
+pub trait KmsClient: Send + Sync {
+    /// Generate a data key of [`DATA_KEY_SIZE`] bytes under the KMS key `key_id`, bound to the given
+    /// encryption context. Corresponds to the `GenerateDataKey` KMS API.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::Kms`] if the key could not be generated.
+    fn generate_data_key(
+        &self,
+        key_id: &str,
+        encryption_context: &HashMap<String, String>,
+    ) -> impl Future<Output = Result<DataKey, Error>> + Send;
+}
+
+impl<T> KmsClient for std::sync::Arc<T>
+where
+    T: KmsClient + ?Sized,
+{
+    async fn generate_data_key(
+        &self,
+        key_id: &str,
+        encryption_context: &HashMap<String, String>,
+    ) -> Result<DataKey, Error> {
+        (**self).generate_data_key(key_id, encryption_context).await
+    }
+}
+
+/// An object-safe form of [`KmsClient`], so a data channel can hold any client without becoming generic
+/// over it.
+pub(crate) trait DynKmsClient: Send + Sync {
+    fn generate_data_key<'a>(
+        &'a self,
+        key_id: &'a str,
+        encryption_context: &'a HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<DataKey, Error>>;
+}
+
+impl<T> DynKmsClient for T
+where
+    T: KmsClient,
+{
+    fn generate_data_key<'a>(
+        &'a self,
+        key_id: &'a str,
+        encryption_context: &'a HashMap<String, String>,
+    ) -> BoxFuture<'a, Result<DataKey, Error>> {
+        Box::pin(KmsClient::generate_data_key(
+            self,
+            key_id,
+            encryption_context,
+        ))
+    }
+}
+
+/// A data key generated by KMS.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey {
+    /// The data key encrypted under the KMS key. This is shared with the agent.
+    pub ciphertext: Vec<u8>,
+    /// The plaintext data key. This never leaves the client.
+    pub plaintext: Vec<u8>,
+}
+
+impl Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataKey")
+            .field("ciphertext", &self.ciphertext)
+            .field("plaintext", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Encrypts and decrypts session data with the keys derived from a [`DataKey`]. Ported from `Encrypter`
+/// in the original implementation.
+pub struct Encrypter {
+    encrypted_data_key: Vec<u8>,
+    encryption_cipher: Aes256Gcm,
+    decryption_cipher: Aes256Gcm,
+}
+
+impl Debug for Encrypter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Encrypter")
+            .field("encrypted_data_key", &self.encrypted_data_key)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Encrypter {
+    /// Create an encrypter from a data key.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidDataKeyLength`] if the plaintext key is not [`DATA_KEY_SIZE`] bytes long.
+    pub fn new(data_key: DataKey) -> Result<Self, Error> {
+        if data_key.plaintext.len() != DATA_KEY_SIZE {
+            Err(Error::InvalidDataKeyLength(data_key.plaintext.len()))?;
+        }
+
+        let (decryption_key, encryption_key) = data_key.plaintext.split_at(DATA_KEY_SIZE / 2);
+
+        Ok(Self {
+            encrypted_data_key: data_key.ciphertext,
+            encryption_cipher: Aes256Gcm::new_from_slice(encryption_key)
+                .map_err(|_| Error::InvalidDataKeyLength(encryption_key.len()))?,
+            decryption_cipher: Aes256Gcm::new_from_slice(decryption_key)
+                .map_err(|_| Error::InvalidDataKeyLength(decryption_key.len()))?,
+        })
+    }
+
+    /// The data key encrypted under the KMS key, which the agent uses to obtain the same data key.
+    #[must_use]
+    pub fn encrypted_data_key(&self) -> &[u8] {
+        &self.encrypted_data_key
+    }
+
+    /// Encrypt data to be sent to the agent. The output is a random nonce followed by the ciphertext.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::Encrypt`] if the data could not be encrypted.
+    pub fn encrypt(&self, plain_text: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        let cipher_text = self
+            .encryption_cipher
+            .encrypt(Nonce::from_slice(&nonce), plain_text)
+            .map_err(|_| Error::Encrypt)?;
+
+        let mut output = Vec::with_capacity(NONCE_SIZE + cipher_text.len());
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&cipher_text);
+
+        Ok(output)
+    }
+
+    /// Decrypt data received from the agent. The input must be a nonce followed by the ciphertext.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::Decrypt`] if the input is too short or fails authentication.
+    pub fn decrypt(&self, cipher_text: &[u8]) -> Result<Vec<u8>, Error> {
+        if cipher_text.len() < NONCE_SIZE {
+            Err(Error::Decrypt)?;
+        }
+
+        let (nonce, cipher_text) = cipher_text.split_at(NONCE_SIZE);
+
+        self.decryption_cipher
+            .decrypt(Nonce::from_slice(nonce), cipher_text)
+            .map_err(|_| Error::Decrypt)
+    }
+
+    /// An encrypter with the keys swapped, as used by the agent on the other end of the channel.
+    #[cfg(test)]
+    pub(crate) fn reversed(&self) -> Self {
+        Self {
+            encrypted_data_key: self.encrypted_data_key.clone(),
+            encryption_cipher: self.decryption_cipher.clone(),
+            decryption_cipher: self.encryption_cipher.clone(),
+        }
+    }
+}
+
+/// An in-process stand-in for KMS, for testing encrypted sessions without AWS credentials. Data keys
+/// are random, and their "encrypted" form is an opaque handle which only this instance can resolve.
+#[derive(Debug, Default)]
+pub struct FakeKmsClient {
+    keys: Mutex<HashMap<Vec<u8>, FakeKmsKey>>,
+}
+
+#[derive(Debug)]
+struct FakeKmsKey {
+    key_id: String,
+    encryption_context: HashMap<String, String>,
+    plaintext: Vec<u8>,
+}
+
+impl FakeKmsClient {
+    /// Create an empty fake KMS.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve an encrypted data key previously returned by this instance, as the agent would with the
+    /// KMS `Decrypt` API. As with KMS, the encryption context must match the one used to generate the key.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::Kms`] if the key is unknown or the encryption context does not match.
+    pub fn decrypt(
+        &self,
+        ciphertext: &[u8],
+        encryption_context: &HashMap<String, String>,
+    ) -> Result<Vec<u8>, Error> {
+        let keys = self.keys.lock().unwrap_or_else(PoisonError::into_inner);
+
+        match keys.get(ciphertext) {
+            Some(key) if key.encryption_context == *encryption_context => Ok(key.plaintext.clone()),
+            Some(key) => Err(Error::Kms(
+                format!("encryption context does not match key {}", key.key_id).into(),
+            )),
+            None => Err(Error::Kms("unknown ciphertext".into())),
+        }
+    }
+}
+
+impl KmsClient for FakeKmsClient {
+    async fn generate_data_key(
+        &self,
+        key_id: &str,
+        encryption_context: &HashMap<String, String>,
+    ) -> Result<DataKey, Error> {
+        let plaintext: [u8; DATA_KEY_SIZE] = rand::random();
+        let ciphertext = [key_id.as_bytes(), b":", &rand::random::<[u8; 16]>()].concat();
+
+        self.keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                ciphertext.clone(),
+                FakeKmsKey {
+                    key_id: key_id.to_string(),
+                    encryption_context: encryption_context.clone(),
+                    plaintext: plaintext.to_vec(),
+                },
+            );
+
+        Ok(DataKey {
+            ciphertext,
+            plaintext: plaintext.to_vec(),
+        })
+    }
+}
+
+/// Errors produced while encrypting a session.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The KMS client failed.
+    #[error("KMS request failed: {0}")]
+    Kms(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// The data key returned by KMS has the wrong length.
+    #[error("Data key must be {DATA_KEY_SIZE} bytes long. Found {0} bytes")]
+    InvalidDataKeyLength(usize),
+
+    /// Data could not be encrypted.
+    #[error("Error encrypting data")]
+    Encrypt,
+
+    /// Data could not be decrypted.
+    #[error("Error decrypting data")]
+    Decrypt,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DATA_KEY_SIZE, DataKey, Encrypter, Error, FakeKmsClient, KmsClient};
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn encrypt_round_trip() {
+        let kms = FakeKmsClient::new();
+        let context = HashMap::from([("aws:ssm:SessionId".to_string(), "session".to_string())]);
+        let data_key = kms
+            .generate_data_key("key-id", &context)
+            .await
+            .expect("Fake KMS should generate a key.");
+        let plaintext = kms
+            .decrypt(&data_key.ciphertext, &context)
+            .expect("Fake KMS should resolve its own key.");
+        assert_eq!(plaintext, data_key.plaintext);
+
+        let client = Encrypter::new(data_key).expect("Data key should be valid.");
+        let agent = client.reversed();
+
+        let cipher_text = client
+            .encrypt(b"payload")
+            .expect("Encryption should succeed.");
+        assert_ne!(&cipher_text[super::NONCE_SIZE..], b"payload");
+        assert_eq!(
+            agent
+                .decrypt(&cipher_text)
+                .expect("Decryption should succeed."),
+            b"payload"
+        );
+        assert!(matches!(client.decrypt(&cipher_text), Err(Error::Decrypt)));
+        assert!(matches!(client.decrypt(b"short"), Err(Error::Decrypt)));
+    }
+
+    #[test]
+    fn reject_invalid_data_key() {
+        let result = Encrypter::new(DataKey {
+            ciphertext: Vec::new(),
+            plaintext: vec![0; DATA_KEY_SIZE / 2],
+        });
+
+        assert!(matches!(result, Err(Error::InvalidDataKeyLength(32))));
+    }
+
+    #[tokio::test]
+    async fn fake_kms_checks_encryption_context() {
+        let kms = FakeKmsClient::new();
+        let data_key = kms
+            .generate_data_key("key-id", &HashMap::new())
+            .await
+            .expect("Fake KMS should generate a key.");
+
+        let context = HashMap::from([("aws:ssm:TargetId".to_string(), "i-0123".to_string())]);
+
+        assert!(matches!(
+            kms.decrypt(&data_key.ciphertext, &context),
+            Err(Error::Kms(_))
+        ));
+    }
+}
@@ -33,4 +33,88 @@ pub enum Error {
     /// TODO
     #[error("Attempted to construct an invalid client message: {0}")]
     InvalidClientMessage(#[source] crate::message::Error),
+
+    /// A message received through the data channel could not be parsed.
+    #[error("Cannot deserialize client message with error: {0}")]
+    MessageDeserialization(#[source] crate::message::ParseError),
+
+    /// The agent requested a session type this client does not support.
+    #[error("Unknown session type {0}")]
+    UnknownSessionType(String),
+
+    /// Session data could not be encrypted or decrypted.
+    #[error("Session encryption failed: {0}")]
+    Encryption(#[source] crate::encryption::Error),
+
+    /// The agent requested KMS encryption but no [`crate::encryption::KmsClient`] was provided.
+    #[error("KMS encryption was requested but no KMS client is configured")]
+    MissingKmsClient,
+
+    /// The agent sent an encryption challenge before encryption was enabled.
+    #[error("Received an encryption challenge but encryption is not enabled")]
+    EncryptionNotEnabled,
+
+    /// The session requires a feature that the calling version of the AWS CLI does not support.
+    #[error(
+        "Installed version of CLI does not support Session Manager encryption feature. Please upgrade to the latest version of your CLI (e.g., AWS CLI)."
+    )]
+    AwsCliUpgradeNeeded,
+
+    /// The websocket connection failed.
+    #[error("Websocket error: {0}")]
+    Websocket(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// The agent or the service closed the websocket connection.
+    #[error("Websocket connection was closed by the remote end")]
+    WebsocketClosed,
+
+    /// The remote end did not answer a keepalive ping in time, so the connection is presumed dead.
+    #[error("No pong received from the remote end within {0:?}")]
+    WebsocketPongTimeout(std::time::Duration),
+
+    /// A stream data message was resent the maximum number of times without being acknowledged, so the
+    /// session is presumed lost.
+    #[error("Stream data message {0} was resent over {max} times without acknowledgement", max = crate::config::RESEND_MAX_ATTEMPT)]
+    StreamMessageResendTimeout(u64),
+
+    /// A task which runs alongside the session panicked or was cancelled.
+    #[error("Session task failed: {0}")]
+    SessionTask(#[source] tokio::task::JoinError),
+
+    /// A message was sent through a websocket channel which is not open.
+    #[error("Websocket channel is not open")]
+    WebsocketNotOpen,
+
+    /// A websocket message type other than [`crate::websocket_channel::TEXT_MESSAGE`] or
+    /// [`crate::websocket_channel::BINARY_MESSAGE`] was requested.
+    #[error("Invalid websocket message type: {0}")]
+    InvalidWebsocketMessageType(u32),
+
+    /// The content of a text message is not valid UTF-8.
+    #[error("Websocket text message is not valid UTF-8: {0}")]
+    InvalidTextMessage(#[source] std::string::FromUtf8Error),
+
+    /// The proxy url is not a valid `http://` url.
+    #[error("Invalid proxy url: {0}")]
+    InvalidProxyUrl(&'static str),
+
+    /// A tunnel through the proxy could not be established.
+    #[error("Failed to connect through proxy: {0}")]
+    ProxyConnect(#[source] std::io::Error),
+
+    /// The proxy refused to open a tunnel, for example because authentication failed.
+    #[error("Proxy refused to open a tunnel with status {0}")]
+    ProxyRejected(u16),
+
+    /// The TLS configuration for the websocket connection is invalid.
+    #[error("Invalid TLS configuration: {0}")]
+    TlsConfig(#[source] crate::websocket_channel::TlsError),
+
+    /// An operation which runs in the background was started outside of a tokio runtime.
+    #[error("Must be called from within a tokio runtime: {0}")]
+    NoRuntime(#[source] tokio::runtime::TryCurrentError),
+
+    /// Output received from the agent could not be written locally.
+    #[error("Failed to write session output: {0}")]
+    WriteOutput(#[source] std::io::Error),
 }
@@ -1,14 +1,22 @@
 #![doc = include_str!("../README.md")]
 #![warn(clippy::all, clippy::pedantic, clippy::cargo)]
+// `multiple_crate_versions` can only be allowed for the whole crate. The duplicates are all transitive and
+// cannot be aligned from here: mio (for tokio's signal driver) and getrandom 0.3 need different versions of
+// wasi, tokio and chrono of windows-link, and tokio, terminal_size and schannel of windows-sys. ring needs
+// getrandom 0.2, and tokio-tungstenite pulls in the webpki-roots 0.26 wrapper around 1.0. Remove once
+// `cargo tree --duplicates -p ssm-lib` is empty.
+#![allow(clippy::multiple_crate_versions)]
 #![warn(missing_docs)]
 
 pub mod config;
 pub mod data_channel;
+pub mod encryption;
 pub mod error;
 pub mod message;
 mod retry;
 mod service;
 pub mod session;
+mod sync;
 pub mod websocket_channel;
 
 pub use error::Error;
@@ -6,85 +6,272 @@ use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use uuid::Uuid;
 
+mod builder;
+#[cfg(feature = "codec")]
+mod codec;
+mod handshake;
 mod message_parser;
 
+pub use builder::{ClientMessageBuilder, NoPayload, WithPayload};
+#[cfg(feature = "codec")]
+pub use codec::{ClientMessageCodec, CodecError, DEFAULT_MAX_FRAME_LENGTH};
+pub use handshake::{
+    ActionStatus, ActionType, EncryptionChallengeRequest, EncryptionChallengeResponse,
+    HandshakeCompletePayload, HandshakeRequestPayload, HandshakeResponsePayload,
+    KmsEncryptionRequest, KmsEncryptionResponse, ProcessedClientAction, RequestedClientAction,
+    SessionTypeRequest,
+};
+pub use message_parser::Error as ParseError;
+
 use sha2::{Digest, Sha256};
 
 /// TODO: document
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+///
+/// Payload types are 4 byte integers on the wire. Values this library does not know about, such as those
+/// added by newer agents, are kept as [`PayloadType::Unknown`] so they can be logged and skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub enum PayloadType {
     /// TODO: document
     #[default]
-    Output = 1,
+    Output,
     /// TODO: document
-    Error = 2,
+    Error,
     /// TODO: document
-    Size = 3,
+    Size,
     /// TODO: document
-    Parameter = 4,
+    Parameter,
     /// TODO: document
-    HandshakeRequestPayloadType = 5,
+    HandshakeRequestPayloadType,
     /// TODO: document
-    HandshakeResponsePayloadType = 6,
+    HandshakeResponsePayloadType,
     /// TODO: document
-    HandshakeCompletePayloadType = 7,
+    HandshakeCompletePayloadType,
     /// TODO: document
-    EncChallengeRequest = 8,
+    EncChallengeRequest,
     /// TODO: document
-    EncChallengeResponse = 9,
+    EncChallengeResponse,
     /// TODO: document
-    Flag = 10,
+    Flag,
     /// TODO: document
-    StdErr = 11,
+    StdErr,
     /// TODO: document
-    ExitCode = 12,
+    ExitCode,
+    /// A payload type this library does not know about, holding the value found on the wire.
+    Unknown(u32),
+}
+
+impl From<PayloadType> for u32 {
+    fn from(value: PayloadType) -> Self {
+        match value {
+            PayloadType::Output => 1,
+            PayloadType::Error => 2,
+            PayloadType::Size => 3,
+            PayloadType::Parameter => 4,
+            PayloadType::HandshakeRequestPayloadType => 5,
+            PayloadType::HandshakeResponsePayloadType => 6,
+            PayloadType::HandshakeCompletePayloadType => 7,
+            PayloadType::EncChallengeRequest => 8,
+            PayloadType::EncChallengeResponse => 9,
+            PayloadType::Flag => 10,
+            PayloadType::StdErr => 11,
+            PayloadType::ExitCode => 12,
+            PayloadType::Unknown(value) => value,
+        }
+    }
+}
+
+impl PayloadType {
+    /// Convert a payload type read from the wire, keeping values this library does not know about as
+    /// [`PayloadType::Unknown`]. Converting the result back into a `u32` gives `value`.
+    #[must_use]
+    pub fn from_wire(value: u32) -> Self {
+        match value {
+            1 => Self::Output,
+            2 => Self::Error,
+            3 => Self::Size,
+            4 => Self::Parameter,
+            5 => Self::HandshakeRequestPayloadType,
+            6 => Self::HandshakeResponsePayloadType,
+            7 => Self::HandshakeCompletePayloadType,
+            8 => Self::EncChallengeRequest,
+            9 => Self::EncChallengeResponse,
+            10 => Self::Flag,
+            11 => Self::StdErr,
+            12 => Self::ExitCode,
+            value => Self::Unknown(value),
+        }
+    }
+}
+
+/// Only accepts known payload types. Use [`PayloadType::from_wire`] to keep unknown values.
+impl TryFrom<u32> for PayloadType {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self, Error> {
+        match Self::from_wire(value) {
+            Self::Unknown(value) => Err(Error::UnknownPayloadType(value)),
+            payload_type => Ok(payload_type),
+        }
+    }
+}
+
+/// The value carried by a [`PayloadType::Flag`] message. Flags are exchanged by the agent and the client to
+/// control the session and, in port forwarding sessions, individual connections. On the wire a flag is
+/// a 4 byte big-endian integer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadTypeFlag {
+    /// The connection to the local port was closed, so the agent should close its connection to the
+    /// remote port.
+    DisconnectToPort = 1,
+    /// The session is being terminated.
+    TerminateSession = 2,
+    /// The agent could not connect to the remote port.
+    ConnectToPortError = 3,
+}
+
+impl PayloadTypeFlag {
+    /// Encode the flag as the payload of a [`PayloadType::Flag`] message.
+    #[must_use]
+    pub fn encode(self) -> [u8; 4] {
+        (self as u32).to_be_bytes()
+    }
+
+    /// Decode the payload of a [`PayloadType::Flag`] message.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`ParseError::InvalidFlagLength`] if the payload is not 4 bytes long, or
+    /// [`ParseError::UnknownPayloadTypeFlag`] if it does not contain a known flag.
+    pub fn decode(payload: &[u8]) -> Result<Self, ParseError> {
+        let bytes: [u8; 4] = payload
+            .try_into()
+            .map_err(|_| ParseError::InvalidFlagLength(payload.len()))?;
+
+        match u32::from_be_bytes(bytes) {
+            1 => Ok(Self::DisconnectToPort),
+            2 => Ok(Self::TerminateSession),
+            3 => Ok(Self::ConnectToPortError),
+            value => Err(ParseError::UnknownPayloadTypeFlag(value)),
+        }
+    }
+}
+
+/// The payload of a [`PayloadType::Size`] message, which tells the agent the size of the client's terminal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct SizeData {
+    /// The number of columns in the terminal.
+    pub cols: u32,
+    /// The number of rows in the terminal.
+    pub rows: u32,
+}
+
+/// Parse the payload of a [`PayloadType::ExitCode`] message. The agent sends the exit code of the remote
+/// command as a decimal string.
+///
+/// ## Errors
+///
+/// Returns [`ParseError::InvalidExitCode`] if the payload is not a decimal integer.
+pub fn parse_exit_code(payload: &[u8]) -> Result<i32, ParseError> {
+    let text = String::from_utf8_lossy(payload);
+
+    text.trim()
+        .parse()
+        .map_err(|_| ParseError::InvalidExitCode(text.into_owned()))
 }
 
-#[derive(
-    Debug,
-    Serialize,
-    Deserialize,
-    Default,
-    Clone,
-    Copy,
-    PartialEq,
-    Eq,
-    strum::Display,
-    strum::IntoStaticStr,
-)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq, Hash)]
 /// TODO: document
+///
+/// Message types are strings on the wire. Values this library does not know about, such as those added
+/// by newer agents, are kept as [`MessageType::Unknown`] so they can be logged and acknowledged.
 pub enum MessageType {
     /// `InputStreamMessage` represents message type for input data
     #[serde(rename = "input_stream_data")]
-    #[strum(to_string = "input_stream_data")]
     #[default]
     InputStreamMessage,
 
     /// `OutputStreamMessage` represents message type for output data
     #[serde(rename = "output_stream_data")]
-    #[strum(to_string = "output_stream_data")]
     OutputStreamMessage,
 
     /// `AcknowledgeMessage` represents message type for acknowledge
     #[serde(rename = "acknowledge")]
-    #[strum(to_string = "acknowledge")]
     AcknowledgeMessage,
 
     /// `ChannelClosedMessage` represents message type for `ChannelClosed`
     #[serde(rename = "channel_closed")]
-    #[strum(to_string = "channel_closed")]
     ChannelClosedMessage,
 
     /// `StartPublicationMessage` represents the message type that notifies the CLI to start sending stream messages
     #[serde(rename = "start_publication")]
-    #[strum(to_string = "start_publication")]
     StartPublicationMessage,
 
     /// `PausePublicationMessage` represents the message type that notifies the CLI to pause sending stream messages
     /// as the remote data channel is inactive
     #[serde(rename = "pause_publication")]
-    #[strum(to_string = "pause_publication")]
     PausePublicationMessage,
+
+    /// A message type this library does not know about, holding the value found on the wire.
+    #[serde(untagged)]
+    Unknown(String),
+}
+
+impl MessageType {
+    /// The value of the message type on the wire.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::InputStreamMessage => "input_stream_data",
+            Self::OutputStreamMessage => "output_stream_data",
+            Self::AcknowledgeMessage => "acknowledge",
+            Self::ChannelClosedMessage => "channel_closed",
+            Self::StartPublicationMessage => "start_publication",
+            Self::PausePublicationMessage => "pause_publication",
+            Self::Unknown(message_type) => message_type,
+        }
+    }
+
+    /// Convert a message type read from the wire, keeping values this library does not know about as
+    /// [`MessageType::Unknown`]. [`MessageType::as_str`] on the result gives `value`.
+    #[must_use]
+    pub fn from_wire(value: &str) -> Self {
+        match value {
+            "input_stream_data" => Self::InputStreamMessage,
+            "output_stream_data" => Self::OutputStreamMessage,
+            "acknowledge" => Self::AcknowledgeMessage,
+            "channel_closed" => Self::ChannelClosedMessage,
+            "start_publication" => Self::StartPublicationMessage,
+            "pause_publication" => Self::PausePublicationMessage,
+            value => Self::Unknown(value.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for MessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Only accepts known message types. Use [`MessageType::from_wire`] to keep unknown values.
+impl TryFrom<&str> for MessageType {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match Self::from_wire(value) {
+            Self::Unknown(value) => Err(Error::UnknownMessageType(value)),
+            message_type => Ok(message_type),
+        }
+    }
+}
+
+impl std::str::FromStr for MessageType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
 }
 
 #[derive(Debug)]
@@ -98,8 +285,11 @@ pub enum MessageType {
 /// ## Notes
 ///
 /// The original implementation had a `HeaderLength` field, but I couldn't find any use for it in the code so far so for now I removed it.
+///
+/// Messages to be sent should be created with [`ClientMessage::builder`], which guarantees that the header
+/// length, payload length and payload digest are consistent with the payload.
 #[allow(dead_code)]
-#[derive(Default)]
+#[cfg_attr(test, derive(Default))]
 pub struct ClientMessage {
     /// `HeaderLength` is a 4 byte integer that represents the header length.
     header_length: u32,
@@ -122,15 +312,246 @@ pub struct ClientMessage {
     /// Payload length is an 4 byte unsigned integer containing the byte length of data in the Payload field.
     payload_length: u32,
     /// Payload is a variable length byte data.
-    payload: Vec<u8>, // TODO: change to use a slice instead of a Vec<u8> to avoid heap allocation
+    ///
+    /// Received messages can be read without copying the payload through [`ClientMessageRef`].
+    payload: Vec<u8>,
+}
+
+/// A borrowed view of a received [`ClientMessage`]. The header fields are decoded eagerly, but the
+/// payload and payload digest point directly into the buffer the message was received in, so no
+/// allocation is needed to process a frame.
+///
+/// Use [`ClientMessageRef::into_owned`] to convert it to a [`ClientMessage`] when the message must
+/// outlive the receive buffer.
+#[derive(Debug, Clone)]
+pub struct ClientMessageRef<'a> {
+    /// `HeaderLength` is a 4 byte integer that represents the header length.
+    header_length: u32,
+    /// `MessageType` is a 32 byte UTF-8 string containing the message type.
+    message_type: MessageType,
+    /// `SchemaVersion` is a 4 byte integer containing the message schema version number.
+    schema_version: u32,
+    /// `CreatedDate` is an 8 byte integer containing the message create epoch millis in UTC.
+    create_date: DateTime<Utc>,
+    /// `SequenceNumber` is an 8 byte integer containing the message sequence number.
+    sequence_number: i64,
+    /// Flags is an 8 byte unsigned integer containing a packed array of control flags.
+    flags: Flags,
+    /// `MessageId` is a 16 byte UUID identifying this message.
+    message_id: Uuid,
+    /// Payload digest is a 32 byte containing the SHA-256 hash of the payload.
+    payload_digest: &'a [u8],
+    /// The type of data contained in the payload.
+    payload_type: PayloadType,
+    /// Payload length is an 4 byte unsigned integer containing the byte length of data in the Payload field.
+    payload_length: u32,
+    /// Payload is a variable length byte data.
+    payload: &'a [u8],
+}
+
+impl<'a> ClientMessageRef<'a> {
+    /// The type of the message.
+    #[must_use]
+    pub fn message_type(&self) -> &MessageType {
+        &self.message_type
+    }
+
+    /// The message schema version number.
+    #[must_use]
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// The time at which the message was created.
+    #[must_use]
+    pub fn create_date(&self) -> DateTime<Utc> {
+        self.create_date
+    }
+
+    /// The sequence number of the message within its stream.
+    #[must_use]
+    pub fn sequence_number(&self) -> i64 {
+        self.sequence_number
+    }
+
+    /// The control flags of the message.
+    #[must_use]
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// The UUID identifying the message.
+    #[must_use]
+    pub fn message_id(&self) -> Uuid {
+        self.message_id
+    }
+
+    /// The type of data contained in the payload.
+    #[must_use]
+    pub fn payload_type(&self) -> PayloadType {
+        self.payload_type
+    }
+
+    /// The message payload, borrowed from the receive buffer.
+    #[must_use]
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Read the payload of a `channel_closed` message.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidMessageType`] if this is not a `channel_closed` message, or
+    /// [`Error::DeserializeError`] if the payload is not a valid [`ChannelClosed`].
+    pub fn deserialize_channel_closed_message(&self) -> Result<ChannelClosed, Error> {
+        if self.message_type != MessageType::ChannelClosedMessage {
+            Err(Error::InvalidMessageType {
+                expected: MessageType::ChannelClosedMessage,
+                actual: self.message_type.clone(),
+            })?;
+        }
+
+        let message: ChannelClosed = serde_json::from_slice(self.payload)?;
+
+        Ok(message)
+    }
+
+    /// Read the payload of an `acknowledge` message.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidMessageType`] if this is not an `acknowledge` message, or
+    /// [`Error::DeserializeError`] if the payload is not a valid [`AcknowledgeContent`].
+    pub fn deserialize_data_stream_acknowledge_content(&self) -> Result<AcknowledgeContent, Error> {
+        if self.message_type != MessageType::AcknowledgeMessage {
+            Err(Error::InvalidMessageType {
+                expected: MessageType::AcknowledgeMessage,
+                actual: self.message_type.clone(),
+            })?;
+        }
+
+        let message: AcknowledgeContent = serde_json::from_slice(self.payload)?;
+
+        Ok(message)
+    }
+
+    /// Read the payload of a handshake request sent by the agent.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidPayloadType`] if the payload is not a handshake request, or
+    /// [`Error::DeserializeError`] if the payload is not a valid [`HandshakeRequestPayload`].
+    pub fn deserialize_handshake_request(&self) -> Result<HandshakeRequestPayload, Error> {
+        self.deserialize_payload(PayloadType::HandshakeRequestPayloadType)
+    }
+
+    /// Read the payload of a handshake complete message sent by the agent.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidPayloadType`] if the payload is not a handshake complete message, or
+    /// [`Error::DeserializeError`] if the payload is not a valid [`HandshakeCompletePayload`].
+    pub fn deserialize_handshake_complete(&self) -> Result<HandshakeCompletePayload, Error> {
+        self.deserialize_payload(PayloadType::HandshakeCompletePayloadType)
+    }
+
+    /// Read the payload of an encryption challenge sent by the agent.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidPayloadType`] if the payload is not an encryption challenge, or
+    /// [`Error::DeserializeError`] if the payload is not a valid [`EncryptionChallengeRequest`].
+    pub fn deserialize_encryption_challenge_request(
+        &self,
+    ) -> Result<EncryptionChallengeRequest, Error> {
+        self.deserialize_payload(PayloadType::EncChallengeRequest)
+    }
+
+    fn deserialize_payload<T>(&self, expected: PayloadType) -> Result<T, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        if self.payload_type != expected {
+            Err(Error::InvalidPayloadType {
+                expected,
+                actual: self.payload_type,
+            })?;
+        }
+
+        Ok(serde_json::from_slice(self.payload)?)
+    }
+
+    /// Copy the borrowed fields into an owned [`ClientMessage`].
+    #[must_use]
+    pub fn into_owned(self) -> ClientMessage {
+        ClientMessage {
+            header_length: self.header_length,
+            message_type: self.message_type,
+            schema_version: self.schema_version,
+            create_date: self.create_date,
+            sequence_number: self.sequence_number,
+            flags: self.flags,
+            message_id: self.message_id,
+            payload_digest: self.payload_digest.to_vec(),
+            payload_type: self.payload_type,
+            payload_length: self.payload_length,
+            payload: self.payload.to_vec(),
+        }
+    }
+}
+
+impl<'a> From<ClientMessageRef<'a>> for ClientMessage {
+    fn from(value: ClientMessageRef<'a>) -> Self {
+        value.into_owned()
+    }
+}
+
+impl<'a> From<&'a ClientMessage> for ClientMessageRef<'a> {
+    fn from(value: &'a ClientMessage) -> Self {
+        Self {
+            header_length: value.header_length,
+            message_type: value.message_type.clone(),
+            schema_version: value.schema_version,
+            create_date: value.create_date,
+            sequence_number: value.sequence_number,
+            flags: value.flags,
+            message_id: value.message_id,
+            payload_digest: &value.payload_digest,
+            payload_type: value.payload_type,
+            payload_length: value.payload_length,
+            payload: &value.payload,
+        }
+    }
 }
 
 impl ClientMessage {
+    /// Create a [`ClientMessageBuilder`] with default header values.
+    #[must_use]
+    pub fn builder() -> ClientMessageBuilder {
+        ClientMessageBuilder::new()
+    }
+
+    /// Create the `acknowledge` message for a received stream data message. The acknowledgement refers
+    /// to the received message by type, id and sequence number, and is marked as sequential.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::DeserializeError`] if the acknowledge content cannot be encoded as JSON.
+    pub fn acknowledge(received: &ClientMessageRef<'_>) -> Result<Self, Error> {
+        let content = AcknowledgeContent::from(received);
+
+        Ok(Self::builder().acknowledge(&content)?.build())
+    }
+
     /// `new` creates a new `ClientMessage` with the given `message_type` and `flags`. The `create_date` is set to the current UTC time.
     ///
     /// ## Errors
     ///
     /// The payload length must be less than 2^32 - 1. If the payload length is greater than this, an error will be returned.
+    #[deprecated(
+        note = "does not check the payload against the message type; use `ClientMessage::builder` instead"
+    )]
     pub fn new(
         message_type: MessageType,
         flags: Flags,
@@ -147,9 +568,9 @@ impl ClientMessage {
         // Read hash digest and consume hasher
         let payload_digest = hasher.finalize().to_vec();
         let message = Self {
-            header_length: Self::HEADER_LENGTH,
+            header_length: Self::PAYLOAD_LENGTH_OFFSET,
             message_type,
-            schema_version: 1,
+            schema_version: Self::SCHEMA_VERSION,
             create_date: Utc::now(),
             flags,
             message_id: uuid::Uuid::new_v4(),
@@ -163,8 +584,56 @@ impl ClientMessage {
         Ok(message)
     }
 
-    /// Confirm whether the message is valid or not. This matches the original implementation,
-    /// but we intend to remove this in favor or validataing the message in the parser.
+    /// The type of the message.
+    #[must_use]
+    pub fn message_type(&self) -> &MessageType {
+        &self.message_type
+    }
+
+    /// The message schema version number.
+    #[must_use]
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// The time at which the message was created.
+    #[must_use]
+    pub fn create_date(&self) -> DateTime<Utc> {
+        self.create_date
+    }
+
+    /// The sequence number of the message within its stream.
+    #[must_use]
+    pub fn sequence_number(&self) -> i64 {
+        self.sequence_number
+    }
+
+    /// The control flags of the message.
+    #[must_use]
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// The UUID identifying the message.
+    #[must_use]
+    pub fn message_id(&self) -> Uuid {
+        self.message_id
+    }
+
+    /// The type of data contained in the payload.
+    #[must_use]
+    pub fn payload_type(&self) -> PayloadType {
+        self.payload_type
+    }
+
+    /// The message payload.
+    #[must_use]
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Confirm whether the message is valid or not. This matches the original implementation.
+    /// Messages returned by [`ClientMessage::deserialize`] have already been validated.
     ///
     /// ## Errors
     ///
@@ -198,17 +667,10 @@ impl ClientMessage {
     }
 
     #[allow(dead_code)]
-    fn deserialize_data_stream_acknowledge_content(&self) -> Result<AcknowledgeContent, Error> {
-        if self.message_type != MessageType::AcknowledgeMessage {
-            Err(Error::InvalidMessageType {
-                expected: MessageType::AcknowledgeMessage,
-                actual: self.message_type,
-            })?;
-        }
-
-        let message: AcknowledgeContent = serde_json::from_slice(self.payload.as_slice())?;
-
-        Ok(message)
+    pub(crate) fn deserialize_data_stream_acknowledge_content(
+        &self,
+    ) -> Result<AcknowledgeContent, Error> {
+        ClientMessageRef::from(self).deserialize_data_stream_acknowledge_content()
     }
 }
 
@@ -243,10 +705,33 @@ pub enum Error {
         /// The actual message type
         actual: MessageType,
     },
+
+    /// A payload type this library does not know about was converted with `TryFrom`, or used to build a
+    /// stream data message. Use [`ClientMessageBuilder::unchecked_input_stream_data`] or
+    /// [`ClientMessageBuilder::unchecked_output_stream_data`] to send it anyway.
+    #[error("Unknown payload type {0}")]
+    UnknownPayloadType(u32),
+
+    /// A message type this library does not know about was converted with `TryFrom`.
+    #[error("Unknown message type {0}")]
+    UnknownMessageType(String),
+
+    /// The payload of the message is not of the type required by the operation.
+    #[error(
+        "ClientMessage PayloadType is not of type {expected:?}. Found payload type: {actual:?}"
+    )]
+    InvalidPayloadType {
+        /// The expected payload type
+        expected: PayloadType,
+        /// The actual payload type
+        actual: PayloadType,
+    },
 }
 
 #[allow(dead_code)]
 impl ClientMessage {
+    const SCHEMA_VERSION: u32 = 1;
+
     const HEADER_LENGTH: u32 = 4;
     const MESSAGE_TYPE_LENGTH: u32 = 32;
 
@@ -274,11 +759,16 @@ impl ClientMessage {
 
 bitflags! {
     /// Flags is an 8 byte unsigned integer containing a packed array of control flags:
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct Flags: u64 {
         /// Bit 0 is SYN - SYN is set (1) when the recipient should consider Seq to be the first message number in the stream
         const SYN = 0b01;
         /// Bit 1 is FIN - FIN is set (1) when this message is the final message in the sequence.
         const FIN = 0b10;
+
+        // Newer agents may set bits this library does not know about. They are retained so that a message
+        // can be re-serialized, for example when relaying it, without losing them.
+        const _ = !0;
     }
 }
 
@@ -290,13 +780,48 @@ impl Default for Flags {
 
 impl Debug for Flags {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Flags")
+        let mut debug = f.debug_struct("Flags");
+        debug
             .field("SYN", &self.contains(Flags::SYN))
-            .field("FIN", &self.contains(Flags::FIN))
-            .finish()
+            .field("FIN", &self.contains(Flags::FIN));
+
+        let unknown_bits = self.bits() & !(Flags::SYN | Flags::FIN).bits();
+        if unknown_bits != 0 {
+            debug.field("unknown_bits", &format_args!("{unknown_bits:#b}"));
+        }
+
+        debug.finish()
     }
 }
 
+/// `ChannelClosed` is the payload of a `channel_closed` message, which the agent sends when it ends the
+/// session. `Output` contains the reason for closing the channel, if any.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ChannelClosed {
+    /// The message type, which is always `channel_closed`.
+    #[serde(default)]
+    pub message_type: String,
+    /// The id of the message.
+    #[serde(default)]
+    pub message_id: String,
+    /// The time at which the channel was closed.
+    #[serde(default)]
+    pub created_date: String,
+    /// The id of the target of the session.
+    #[serde(default)]
+    pub destination_id: String,
+    /// The id of the session the channel belongs to.
+    #[serde(default)]
+    pub session_id: String,
+    /// The schema version of the message.
+    #[serde(default)]
+    pub schema_version: i32,
+    /// The reason the channel was closed. May be empty.
+    #[serde(default)]
+    pub output: String,
+}
+
 /// `AcknowledgeContent` is used to inform the sender of an acknowledge message that the message has been received.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AcknowledgeContent {
@@ -317,12 +842,49 @@ pub struct AcknowledgeContent {
     is_sequential_message: bool,
 }
 
+impl AcknowledgeContent {
+    /// The type of the message being acknowledged.
+    #[must_use]
+    pub fn message_type(&self) -> &MessageType {
+        &self.message_type
+    }
+
+    /// The id of the message being acknowledged.
+    #[must_use]
+    pub fn message_id(&self) -> Uuid {
+        self.message_id
+    }
+
+    /// The sequence number of the message being acknowledged.
+    #[must_use]
+    pub fn sequence_number(&self) -> i64 {
+        self.sequence_number
+    }
+
+    /// Whether the message being acknowledged is part of a sequence.
+    #[must_use]
+    pub fn is_sequential_message(&self) -> bool {
+        self.is_sequential_message
+    }
+}
+
+impl From<&ClientMessageRef<'_>> for AcknowledgeContent {
+    fn from(message: &ClientMessageRef<'_>) -> Self {
+        Self {
+            message_type: message.message_type.clone(),
+            message_id: message.message_id,
+            sequence_number: message.sequence_number,
+            is_sequential_message: true,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use sha2::{Digest, Sha256};
     use uuid::Uuid;
 
-    use super::ClientMessage;
+    use super::{ClientMessage, Error, MessageType, PayloadType};
 
     const MESSAGE_ID_RAW: &str = "dd01e56b-ff48-483e-a508-b5f073f31b16";
     static MESSAGE_ID: std::sync::LazyLock<Uuid> = std::sync::LazyLock::new(|| {
@@ -345,7 +907,6 @@ mod test {
         .to_vec()
     });
 
-    // TODO: use typestate to make invalid messages impossible to create
     #[test]
     fn test_client_message_validate() {
         let mut message = super::ClientMessage {
@@ -397,6 +958,156 @@ mod test {
         assert!(result.is_ok(), "message should be valid");
     }
 
+    #[test]
+    fn acknowledge_received_message() {
+        let received = ClientMessage::builder()
+            .with_message_id(*MESSAGE_ID)
+            .with_sequence_number(42)
+            .output_stream_data(super::PayloadType::Output, PAYLOAD.to_vec())
+            .expect("payload should fit")
+            .build()
+            .serialize()
+            .expect("message should serialize");
+        let received =
+            super::ClientMessageRef::deserialize(&received).expect("message should be valid");
+
+        let ack = ClientMessage::acknowledge(&received).expect("ack should be created");
+
+        assert_eq!(ack.message_type, super::MessageType::AcknowledgeMessage);
+        ack.validate().expect("ack should be valid");
+
+        let content = ack
+            .deserialize_data_stream_acknowledge_content()
+            .expect("payload should be acknowledge content");
+
+        assert_eq!(
+            *content.message_type(),
+            super::MessageType::OutputStreamMessage
+        );
+        assert_eq!(content.message_id(), *MESSAGE_ID);
+        assert_eq!(content.sequence_number(), 42);
+        assert!(content.is_sequential_message());
+    }
+
+    #[test]
+    fn convert_payload_type() {
+        assert_eq!(PayloadType::try_from(12).unwrap(), PayloadType::ExitCode);
+        assert!(matches!(
+            PayloadType::try_from(13),
+            Err(Error::UnknownPayloadType(13))
+        ));
+
+        for value in [1, 12, 13, u32::MAX] {
+            assert_eq!(u32::from(PayloadType::from_wire(value)), value);
+        }
+        assert_eq!(PayloadType::from_wire(13), PayloadType::Unknown(13));
+    }
+
+    #[test]
+    fn convert_message_type() {
+        assert_eq!(
+            MessageType::try_from("acknowledge").unwrap(),
+            MessageType::AcknowledgeMessage
+        );
+        assert!(matches!(
+            "interactive_shell_data".parse::<MessageType>(),
+            Err(Error::UnknownMessageType(message_type)) if message_type == "interactive_shell_data"
+        ));
+
+        for value in [
+            "input_stream_data",
+            "pause_publication",
+            "interactive_shell_data",
+        ] {
+            assert_eq!(MessageType::from_wire(value).as_str(), value);
+        }
+        assert_eq!(
+            MessageType::from_wire("interactive_shell_data"),
+            MessageType::Unknown("interactive_shell_data".to_string())
+        );
+    }
+
+    #[test]
+    fn encode_payload_type_flag() {
+        let encoded = super::PayloadTypeFlag::TerminateSession.encode();
+
+        assert_eq!(encoded, [0, 0, 0, 2]);
+        assert_eq!(
+            super::PayloadTypeFlag::decode(&encoded).expect("flag should decode"),
+            super::PayloadTypeFlag::TerminateSession
+        );
+        assert!(matches!(
+            super::PayloadTypeFlag::decode(&[0, 0, 0, 9]),
+            Err(super::ParseError::UnknownPayloadTypeFlag(9))
+        ));
+        assert!(matches!(
+            super::PayloadTypeFlag::decode(&[0, 1]),
+            Err(super::ParseError::InvalidFlagLength(2))
+        ));
+    }
+
+    #[test]
+    fn debug_flags_shows_unknown_bits() {
+        assert_eq!(
+            format!("{:?}", super::Flags::SYN),
+            "Flags { SYN: true, FIN: false }"
+        );
+        assert_eq!(
+            format!("{:?}", super::Flags::from_bits_retain(0b1110)),
+            "Flags { SYN: false, FIN: true, unknown_bits: 0b1100 }"
+        );
+    }
+
+    #[test]
+    fn parse_exit_code() {
+        assert_eq!(super::parse_exit_code(b"0").expect("should parse"), 0);
+        assert_eq!(super::parse_exit_code(b"127\n").expect("should parse"), 127);
+        assert_eq!(super::parse_exit_code(b"-1").expect("should parse"), -1);
+        assert!(matches!(
+            super::parse_exit_code(b"failed"),
+            Err(super::ParseError::InvalidExitCode(_))
+        ));
+    }
+
+    #[test]
+    fn deserialize_channel_closed_message() {
+        let payload = br#"{
+            "MessageType": "channel_closed",
+            "MessageId": "dd01e56b-ff48-483e-a508-b5f073f31b16",
+            "CreatedDate": "2025-01-01T00:00:00.000Z",
+            "DestinationId": "i-0123abc",
+            "SessionId": "session-id",
+            "SchemaVersion": 1,
+            "Output": "Session terminated"
+        }"#;
+        let owned = ClientMessage {
+            message_type: super::MessageType::ChannelClosedMessage,
+            payload: payload.to_vec(),
+            ..Default::default()
+        };
+        let message = super::ClientMessageRef::from(&owned);
+
+        let result = message
+            .deserialize_channel_closed_message()
+            .expect("payload should be valid");
+
+        assert_eq!(result.message_id, MESSAGE_ID_RAW);
+        assert_eq!(result.destination_id, "i-0123abc");
+        assert_eq!(result.session_id, "session-id");
+        assert_eq!(result.schema_version, 1);
+        assert_eq!(result.output, "Session terminated");
+
+        let message = super::ClientMessageRef {
+            message_type: super::MessageType::OutputStreamMessage,
+            ..message
+        };
+
+        assert!(matches!(
+            message.deserialize_channel_closed_message(),
+            Err(super::Error::InvalidMessageType { .. })
+        ));
+    }
+
     #[test]
     fn test_deserialize_data_stream_acknowledge_content() {
         let mut test_message = ClientMessage {
@@ -0,0 +1,497 @@
+//! A typestate builder for [`ClientMessage`]. The builder only exposes `build` once a payload that is
+//! valid for the chosen message type has been provided, and it computes the header length, payload
+//! length and payload digest itself, so every message it produces passes [`ClientMessage::validate`].
+//!
+//! Stream data payloads are checked against their payload type. Use the typed methods such as
+//! [`ClientMessageBuilder::size`] to have the builder encode the payload. Payloads the builder does not
+//! understand can only be sent by opting into
+//! [`ClientMessageBuilder::unchecked_input_stream_data`] or
+//! [`ClientMessageBuilder::unchecked_output_stream_data`].
+
+use super::{
+    AcknowledgeContent, ChannelClosed, ClientMessage, EncryptionChallengeRequest,
+    EncryptionChallengeResponse, Error, Flags, HandshakeCompletePayload, HandshakeRequestPayload,
+    HandshakeResponsePayload, MessageType, PayloadType, PayloadTypeFlag, SizeData,
+};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Builder for [`ClientMessage`]. Create one with [`ClientMessage::builder`].
+///
+/// The `State` parameter tracks whether a payload has been provided. Header fields which have a sensible
+/// default can be set at any point, while the message type and payload are set together through one of
+/// the type-specific methods such as [`ClientMessageBuilder::input_stream_data`].
+#[derive(Debug)]
+pub struct ClientMessageBuilder<State = NoPayload> {
+    schema_version: u32,
+    create_date: DateTime<Utc>,
+    sequence_number: i64,
+    flags: Flags,
+    message_id: Uuid,
+    state: State,
+}
+
+/// Builder state before a message type and payload have been provided.
+#[derive(Debug, Default)]
+pub struct NoPayload;
+
+/// Builder state once a message type and a payload that is valid for it have been provided.
+#[derive(Debug)]
+pub struct WithPayload {
+    message_type: MessageType,
+    payload_type: PayloadType,
+    payload: Vec<u8>,
+    payload_length: u32,
+}
+
+impl WithPayload {
+    fn new(
+        message_type: MessageType,
+        payload_type: PayloadType,
+        payload: Vec<u8>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            message_type,
+            payload_type,
+            payload_length: u32::try_from(payload.len())?,
+            payload,
+        })
+    }
+}
+
+impl Default for ClientMessageBuilder<NoPayload> {
+    fn default() -> Self {
+        Self {
+            schema_version: ClientMessage::SCHEMA_VERSION,
+            create_date: Utc::now(),
+            sequence_number: 0,
+            flags: Flags::empty(),
+            message_id: Uuid::new_v4(),
+            state: NoPayload,
+        }
+    }
+}
+
+impl<State> ClientMessageBuilder<State> {
+    /// Set the sequence number. Defaults to 0, which is what the original implementation uses for
+    /// messages that are not part of a stream.
+    #[must_use]
+    pub fn with_sequence_number(mut self, sequence_number: i64) -> Self {
+        self.sequence_number = sequence_number;
+        self
+    }
+
+    /// Set the control flags. Defaults to no flags, except for acknowledge messages.
+    #[must_use]
+    pub fn with_flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set the message id. Defaults to a random UUID.
+    #[must_use]
+    pub fn with_message_id(mut self, message_id: Uuid) -> Self {
+        self.message_id = message_id;
+        self
+    }
+
+    /// Set the creation time. Defaults to the time the builder was created.
+    #[must_use]
+    pub fn with_create_date(mut self, create_date: DateTime<Utc>) -> Self {
+        self.create_date = create_date;
+        self
+    }
+
+    fn with_state<S>(self, state: S) -> ClientMessageBuilder<S> {
+        ClientMessageBuilder {
+            schema_version: self.schema_version,
+            create_date: self.create_date,
+            sequence_number: self.sequence_number,
+            flags: self.flags,
+            message_id: self.message_id,
+            state,
+        }
+    }
+}
+
+impl ClientMessageBuilder<NoPayload> {
+    /// Create a builder with default header values.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build an `input_stream_data` message, which carries data from the client to the agent. The payload
+    /// is checked against `payload_type`:
+    ///
+    /// * [`PayloadType::Size`] and the handshake and encryption challenge payloads are valid JSON for
+    ///   their types.
+    /// * [`PayloadType::Flag`] payloads hold a known [`PayloadTypeFlag`].
+    /// * [`PayloadType::ExitCode`] payloads hold a decimal integer.
+    /// * The payload type is not [`PayloadType::Unknown`].
+    ///
+    /// Any other payload, such as [`PayloadType::Output`], may hold arbitrary bytes.
+    ///
+    /// ## Errors
+    ///
+    /// * [`Error::UnknownPayloadType`], [`Error::ParseError`] or [`Error::DeserializeError`] if the payload
+    ///   is not valid for `payload_type`.
+    /// * [`Error::InvalidPayloadLength`] if the payload is longer than [`u32::MAX`] bytes.
+    pub fn input_stream_data(
+        self,
+        payload_type: PayloadType,
+        payload: Vec<u8>,
+    ) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        validate_payload(payload_type, &payload)?;
+
+        self.stream_data(MessageType::InputStreamMessage, payload_type, payload)
+    }
+
+    /// Build an `output_stream_data` message, which carries data from the agent to the client.
+    ///
+    /// ## Errors
+    ///
+    /// * [`Error::UnknownPayloadType`], [`Error::ParseError`] or [`Error::DeserializeError`] if the payload
+    ///   is not valid for `payload_type`. See [`Self::input_stream_data`] for the rules.
+    /// * [`Error::InvalidPayloadLength`] if the payload is longer than [`u32::MAX`] bytes.
+    pub fn output_stream_data(
+        self,
+        payload_type: PayloadType,
+        payload: Vec<u8>,
+    ) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        validate_payload(payload_type, &payload)?;
+
+        self.stream_data(MessageType::OutputStreamMessage, payload_type, payload)
+    }
+
+    /// Build an `input_stream_data` message without checking the payload against `payload_type`. This is
+    /// an escape hatch for payloads the builder does not understand, such as unknown payload types. The
+    /// agent may reject the message.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidPayloadLength`] if the payload is longer than [`u32::MAX`] bytes.
+    pub fn unchecked_input_stream_data(
+        self,
+        payload_type: PayloadType,
+        payload: Vec<u8>,
+    ) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        self.stream_data(MessageType::InputStreamMessage, payload_type, payload)
+    }
+
+    /// Build an `output_stream_data` message without checking the payload against `payload_type`, for
+    /// example to test how malformed messages from the agent are handled.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidPayloadLength`] if the payload is longer than [`u32::MAX`] bytes.
+    pub fn unchecked_output_stream_data(
+        self,
+        payload_type: PayloadType,
+        payload: Vec<u8>,
+    ) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        self.stream_data(MessageType::OutputStreamMessage, payload_type, payload)
+    }
+
+    /// Build an `input_stream_data` message which tells the agent the size of the client's terminal.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::DeserializeError`] if the size cannot be encoded as JSON.
+    pub fn size(self, size: SizeData) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        let payload = serde_json::to_vec(&size)?;
+
+        self.stream_data(MessageType::InputStreamMessage, PayloadType::Size, payload)
+    }
+
+    /// Build an `input_stream_data` message which sends `flag` to the agent.
+    #[must_use]
+    pub fn flag(self, flag: PayloadTypeFlag) -> ClientMessageBuilder<WithPayload> {
+        self.with_state(WithPayload {
+            message_type: MessageType::InputStreamMessage,
+            payload_type: PayloadType::Flag,
+            payload: flag.encode().to_vec(),
+            payload_length: 4,
+        })
+    }
+
+    /// Build an `output_stream_data` message which reports the exit code of the remote command, as the
+    /// agent does when a non-interactive command finishes.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidPayloadLength`] if the payload is longer than [`u32::MAX`] bytes.
+    pub fn exit_code(self, exit_code: i32) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        let payload = exit_code.to_string().into_bytes();
+
+        self.stream_data(
+            MessageType::OutputStreamMessage,
+            PayloadType::ExitCode,
+            payload,
+        )
+    }
+
+    fn stream_data(
+        self,
+        message_type: MessageType,
+        payload_type: PayloadType,
+        payload: Vec<u8>,
+    ) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        let state = WithPayload::new(message_type, payload_type, payload)?;
+
+        Ok(self.with_state(state))
+    }
+
+    /// Build an `acknowledge` message whose payload is the JSON encoded `content`. As in the original
+    /// implementation, the SYN and FIN flags are set unless overridden with [`Self::with_flags`], and the
+    /// payload type is left unset, which is 0 on the wire.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::DeserializeError`] if the content cannot be encoded as JSON.
+    pub fn acknowledge(
+        self,
+        content: &AcknowledgeContent,
+    ) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        let payload = serde_json::to_vec(content)?;
+        let state = WithPayload::new(
+            MessageType::AcknowledgeMessage,
+            PayloadType::from_wire(0),
+            payload,
+        )?;
+
+        Ok(self.with_flags(Flags::SYN | Flags::FIN).with_state(state))
+    }
+
+    /// Build a `channel_closed` message whose payload is the JSON encoded `content`.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::DeserializeError`] if the content cannot be encoded as JSON.
+    pub fn channel_closed(
+        self,
+        content: &ChannelClosed,
+    ) -> Result<ClientMessageBuilder<WithPayload>, Error> {
+        let payload = serde_json::to_vec(content)?;
+        let state = WithPayload::new(
+            MessageType::ChannelClosedMessage,
+            PayloadType::default(),
+            payload,
+        )?;
+
+        Ok(self.with_state(state))
+    }
+
+    /// Build a `start_publication` message. Publication messages do not carry a payload.
+    #[must_use]
+    pub fn start_publication(self) -> ClientMessageBuilder<WithPayload> {
+        self.with_state(Self::publication(MessageType::StartPublicationMessage))
+    }
+
+    /// Build a `pause_publication` message. Publication messages do not carry a payload.
+    #[must_use]
+    pub fn pause_publication(self) -> ClientMessageBuilder<WithPayload> {
+        self.with_state(Self::publication(MessageType::PausePublicationMessage))
+    }
+
+    fn publication(message_type: MessageType) -> WithPayload {
+        WithPayload {
+            message_type,
+            payload_type: PayloadType::default(),
+            payload: Vec::new(),
+            payload_length: 0,
+        }
+    }
+}
+
+/// Check that `payload` is valid for `payload_type`, following the rules on
+/// [`ClientMessageBuilder::input_stream_data`].
+fn validate_payload(payload_type: PayloadType, payload: &[u8]) -> Result<(), Error> {
+    match payload_type {
+        PayloadType::Size => serde_json::from_slice::<SizeData>(payload).map(drop)?,
+        PayloadType::HandshakeRequestPayloadType => {
+            serde_json::from_slice::<HandshakeRequestPayload>(payload).map(drop)?;
+        }
+        PayloadType::HandshakeResponsePayloadType => {
+            serde_json::from_slice::<HandshakeResponsePayload>(payload).map(drop)?;
+        }
+        PayloadType::HandshakeCompletePayloadType => {
+            serde_json::from_slice::<HandshakeCompletePayload>(payload).map(drop)?;
+        }
+        PayloadType::EncChallengeRequest => {
+            serde_json::from_slice::<EncryptionChallengeRequest>(payload).map(drop)?;
+        }
+        PayloadType::EncChallengeResponse => {
+            serde_json::from_slice::<EncryptionChallengeResponse>(payload).map(drop)?;
+        }
+        PayloadType::Flag => PayloadTypeFlag::decode(payload).map(drop)?,
+        PayloadType::ExitCode => super::parse_exit_code(payload).map(drop)?,
+        PayloadType::Unknown(payload_type) => Err(Error::UnknownPayloadType(payload_type))?,
+        PayloadType::Output | PayloadType::Error | PayloadType::Parameter | PayloadType::StdErr => {
+        }
+    }
+
+    Ok(())
+}
+
+impl ClientMessageBuilder<WithPayload> {
+    /// Convert the builder into a [`ClientMessage`].
+    #[must_use]
+    pub fn build(self) -> ClientMessage {
+        let WithPayload {
+            message_type,
+            payload_type,
+            payload,
+            payload_length,
+        } = self.state;
+
+        let mut hasher = Sha256::new();
+        hasher.update(&payload);
+
+        ClientMessage {
+            header_length: ClientMessage::PAYLOAD_LENGTH_OFFSET,
+            message_type,
+            schema_version: self.schema_version,
+            create_date: self.create_date,
+            sequence_number: self.sequence_number,
+            flags: self.flags,
+            message_id: self.message_id,
+            payload_digest: hasher.finalize().to_vec(),
+            payload_type,
+            payload_length,
+            payload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message::{
+        ClientMessage, Error, Flags, MessageType, ParseError, PayloadType, PayloadTypeFlag,
+        SizeData, parse_exit_code,
+    };
+    use uuid::Uuid;
+
+    #[test]
+    fn build_input_stream_data() {
+        let message_id = Uuid::new_v4();
+
+        let message = ClientMessage::builder()
+            .with_sequence_number(3)
+            .with_message_id(message_id)
+            .input_stream_data(PayloadType::Output, b"payload".to_vec())
+            .expect("payload should fit")
+            .build();
+
+        message.validate().expect("built message should be valid");
+        assert_eq!(*message.message_type(), MessageType::InputStreamMessage);
+        assert_eq!(message.payload_type(), PayloadType::Output);
+        assert_eq!(message.sequence_number(), 3);
+        assert_eq!(message.message_id(), message_id);
+        assert_eq!(message.flags(), Flags::empty());
+        assert_eq!(message.payload(), b"payload");
+        assert_eq!(message.header_length, ClientMessage::PAYLOAD_LENGTH_OFFSET);
+    }
+
+    #[test]
+    fn reject_payloads_invalid_for_their_type() {
+        assert!(matches!(
+            ClientMessage::builder().input_stream_data(PayloadType::Size, b"payload".to_vec()),
+            Err(Error::DeserializeError(_))
+        ));
+        assert!(matches!(
+            ClientMessage::builder().input_stream_data(PayloadType::Flag, vec![0, 0, 0, 9]),
+            Err(Error::ParseError(ParseError::UnknownPayloadTypeFlag(9)))
+        ));
+        assert!(matches!(
+            ClientMessage::builder().output_stream_data(PayloadType::ExitCode, b"one".to_vec()),
+            Err(Error::ParseError(ParseError::InvalidExitCode(_)))
+        ));
+        assert!(matches!(
+            ClientMessage::builder()
+                .output_stream_data(PayloadType::HandshakeRequestPayloadType, b"{".to_vec()),
+            Err(Error::DeserializeError(_))
+        ));
+        assert!(matches!(
+            ClientMessage::builder().output_stream_data(PayloadType::Unknown(13), Vec::new()),
+            Err(Error::UnknownPayloadType(13))
+        ));
+
+        let message = ClientMessage::builder()
+            .unchecked_output_stream_data(PayloadType::Unknown(13), b"payload".to_vec())
+            .expect("unchecked payloads are not checked")
+            .build();
+        message.validate().expect("built message should be valid");
+        assert_eq!(message.payload_type(), PayloadType::Unknown(13));
+    }
+
+    #[test]
+    fn build_typed_payloads() {
+        let size = SizeData { cols: 80, rows: 24 };
+        let message = ClientMessage::builder()
+            .size(size)
+            .expect("size should serialize")
+            .build();
+        message.validate().expect("built message should be valid");
+        assert_eq!(*message.message_type(), MessageType::InputStreamMessage);
+        assert_eq!(message.payload_type(), PayloadType::Size);
+        assert_eq!(
+            serde_json::from_slice::<SizeData>(message.payload()).unwrap(),
+            size
+        );
+
+        let message = ClientMessage::builder()
+            .flag(PayloadTypeFlag::TerminateSession)
+            .build();
+        message.validate().expect("built message should be valid");
+        assert_eq!(message.payload_type(), PayloadType::Flag);
+        assert_eq!(
+            PayloadTypeFlag::decode(message.payload()).unwrap(),
+            PayloadTypeFlag::TerminateSession
+        );
+
+        let message = ClientMessage::builder()
+            .exit_code(-2)
+            .expect("exit code should fit")
+            .build();
+        message.validate().expect("built message should be valid");
+        assert_eq!(*message.message_type(), MessageType::OutputStreamMessage);
+        assert_eq!(message.payload_type(), PayloadType::ExitCode);
+        assert_eq!(parse_exit_code(message.payload()).unwrap(), -2);
+    }
+
+    #[test]
+    fn build_acknowledge() {
+        let message = ClientMessage::builder()
+            .acknowledge(&super::AcknowledgeContent {
+                message_type: MessageType::OutputStreamMessage,
+                message_id: Uuid::new_v4(),
+                sequence_number: 5,
+                is_sequential_message: true,
+            })
+            .expect("content should serialize")
+            .build();
+
+        message.validate().expect("built message should be valid");
+        assert_eq!(message.flags(), Flags::SYN | Flags::FIN);
+        assert_eq!(u32::from(message.payload_type()), 0);
+
+        let content = message
+            .deserialize_data_stream_acknowledge_content()
+            .expect("payload should be acknowledge content");
+        assert_eq!(content.sequence_number, 5);
+    }
+
+    #[test]
+    fn build_publication() {
+        let message = ClientMessage::builder().pause_publication().build();
+
+        message.validate().expect("built message should be valid");
+        assert_eq!(
+            *message.message_type(),
+            MessageType::PausePublicationMessage
+        );
+        assert!(message.payload().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+//! A [`tokio_util::codec`] codec for [`ClientMessage`] frames, so the protocol can run over transports
+//! other than [`crate::websocket_channel::WebsocketChannel`].
+//!
+//! Frames are self-delimiting: the header records its own length and the length of the payload that
+//! follows it, so [`ClientMessageCodec`] can be used with [`tokio_util::codec::Framed`] over any
+//! `AsyncRead + AsyncWrite` byte stream. Transports that are already message oriented, such as a
+//! websocket, carry one frame per binary message and can call [`Decoder::decode`] and
+//! [`Encoder::encode`] on each message directly.
+
+use super::{ClientMessage, ParseError};
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The largest frame accepted by [`ClientMessageCodec::new`]. Matches the default of
+/// [`tokio_util::codec::LengthDelimitedCodec`].
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// Encodes and decodes [`ClientMessage`] frames in the binary format used by MGS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientMessageCodec {
+    max_frame_length: usize,
+}
+
+impl Default for ClientMessageCodec {
+    fn default() -> Self {
+        Self {
+            max_frame_length: DEFAULT_MAX_FRAME_LENGTH,
+        }
+    }
+}
+
+impl ClientMessageCodec {
+    /// Create a codec which accepts frames of up to [`DEFAULT_MAX_FRAME_LENGTH`] bytes.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the largest frame, header included, which the codec will decode or encode. Guards against
+    /// buffering an unbounded amount of data because of a corrupt or malicious length field.
+    #[must_use]
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = max_frame_length;
+        self
+    }
+
+    /// The largest frame the codec will decode or encode.
+    #[must_use]
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+
+    /// The length of the frame at the start of `src`, which must hold at least a full header.
+    fn frame_length(src: &[u8]) -> usize {
+        let read_u32 = |offset: u32| {
+            let offset = offset as usize;
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&src[offset..offset + 4]);
+            u32::from_be_bytes(bytes) as usize
+        };
+
+        let header_length = read_u32(ClientMessage::HEADER_OFFSET);
+        let payload_length = read_u32(ClientMessage::PAYLOAD_LENGTH_OFFSET);
+
+        // The payload starts after the header and the payload length field, as in `get_payload`.
+        (header_length + ClientMessage::PAYLOAD_LENGTH_LENGTH as usize + payload_length)
+            .max(ClientMessage::PAYLOAD_OFFSET as usize)
+    }
+}
+
+impl Decoder for ClientMessageCodec {
+    type Item = ClientMessage;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let header_end = ClientMessage::PAYLOAD_OFFSET as usize;
+        if src.len() < header_end {
+            src.reserve(header_end - src.len());
+            return Ok(None);
+        }
+
+        let frame_length = Self::frame_length(src);
+        if frame_length > self.max_frame_length {
+            Err(CodecError::FrameTooLarge {
+                length: frame_length,
+                max: self.max_frame_length,
+            })?;
+        }
+
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(frame_length);
+
+        Ok(Some(ClientMessage::deserialize(&frame)?))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(message) => Ok(Some(message)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                let header_end = ClientMessage::PAYLOAD_OFFSET as usize;
+                let expected = if src.len() < header_end {
+                    header_end
+                } else {
+                    Self::frame_length(src)
+                };
+                let actual = src.len();
+                src.clear();
+
+                Err(ParseError::IncompleteMessage { expected, actual })?
+            }
+        }
+    }
+}
+
+impl Encoder<&ClientMessage> for ClientMessageCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &ClientMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let frame = item.serialize_inner().map_err(CodecError::Serialize)?;
+        if frame.len() > self.max_frame_length {
+            Err(CodecError::FrameTooLarge {
+                length: frame.len(),
+                max: self.max_frame_length,
+            })?;
+        }
+
+        dst.reserve(frame.len());
+        dst.put_slice(&frame);
+
+        Ok(())
+    }
+}
+
+impl Encoder<ClientMessage> for ClientMessageCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: ClientMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode(&item, dst)
+    }
+}
+
+/// Errors produced by [`ClientMessageCodec`].
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    /// The underlying transport failed.
+    #[error("Transport error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// A received frame could not be parsed.
+    #[error("Failed to decode ClientMessage: {0}")]
+    Parse(#[from] ParseError),
+
+    /// A message could not be serialized.
+    #[error("Failed to encode ClientMessage: {0}")]
+    Serialize(#[source] super::Error),
+
+    /// A frame exceeds the codec's maximum frame length.
+    #[error("Frame of {length} bytes exceeds the maximum frame length of {max} bytes")]
+    FrameTooLarge {
+        /// The length of the frame
+        length: usize,
+        /// The maximum frame length of the codec
+        max: usize,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientMessageCodec, CodecError};
+    use crate::message::{ClientMessage, ParseError, PayloadType};
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn message(sequence_number: i64, payload: &[u8]) -> ClientMessage {
+        ClientMessage::builder()
+            .with_sequence_number(sequence_number)
+            .output_stream_data(PayloadType::Output, payload.to_vec())
+            .expect("payload should fit")
+            .build()
+    }
+
+    #[test]
+    fn round_trip_across_partial_reads() {
+        let mut codec = ClientMessageCodec::new();
+        let mut encoded = BytesMut::new();
+        codec
+            .encode(message(1, b"first"), &mut encoded)
+            .expect("message should encode");
+        codec
+            .encode(&message(2, b"second"), &mut encoded)
+            .expect("message should encode");
+
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for chunk in encoded.chunks(7) {
+            src.extend_from_slice(chunk);
+            while let Some(message) = codec.decode(&mut src).expect("frame should decode") {
+                decoded.push(message);
+            }
+        }
+
+        assert!(src.is_empty());
+        let decoded: Vec<_> = decoded
+            .iter()
+            .map(|message| (message.sequence_number(), message.payload().to_vec()))
+            .collect();
+        assert_eq!(decoded, [(1, b"first".to_vec()), (2, b"second".to_vec())]);
+    }
+
+    #[test]
+    fn reject_frames_over_max_length() {
+        let mut encoded = BytesMut::new();
+        ClientMessageCodec::new()
+            .encode(message(1, &[0; 64]), &mut encoded)
+            .expect("message should encode");
+
+        let mut codec = ClientMessageCodec::new().with_max_frame_length(encoded.len() - 1);
+
+        assert!(matches!(
+            codec.decode(&mut encoded.clone()),
+            Err(CodecError::FrameTooLarge { .. })
+        ));
+        assert!(matches!(
+            codec.encode(message(1, &[0; 64]), &mut BytesMut::new()),
+            Err(CodecError::FrameTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_frame_at_eof_is_an_error() {
+        let mut encoded = BytesMut::new();
+        ClientMessageCodec::new()
+            .encode(message(1, b"payload"), &mut encoded)
+            .expect("message should encode");
+        encoded.truncate(encoded.len() - 1);
+        let frame_length = encoded.len() + 1;
+
+        let result = ClientMessageCodec::new().decode_eof(&mut encoded);
+
+        assert!(matches!(
+            result,
+            Err(CodecError::Parse(ParseError::IncompleteMessage { expected, .. }))
+                if expected == frame_length
+        ));
+        assert!(encoded.is_empty());
+    }
+}
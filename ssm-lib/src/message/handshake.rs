@@ -0,0 +1,327 @@
+//! Payloads exchanged during the session handshake. The agent opens the handshake with a
+//! [`HandshakeRequestPayload`] listing the actions it needs the client to perform, the client replies
+//! with a [`HandshakeResponsePayload`] describing how each action was processed, and the agent ends the
+//! handshake with a [`HandshakeCompletePayload`].
+//!
+//! Ported from the handshake types in `message/clientmessage.go` in the original implementation.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::Duration;
+
+/// The type of an action the agent requests the client to perform during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ActionType {
+    /// Enable KMS encryption of the session data.
+    #[serde(rename = "KMSEncryption")]
+    KmsEncryption,
+    /// Inform the client of the type of session, such as a shell or a port forwarding session.
+    SessionType,
+    /// An action this client does not know about. The name is kept so it can be reported back to the agent.
+    #[serde(untagged)]
+    Unknown(String),
+}
+
+impl std::fmt::Display for ActionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KmsEncryption => f.write_str("KMSEncryption"),
+            Self::SessionType => f.write_str("SessionType"),
+            Self::Unknown(action_type) => f.write_str(action_type),
+        }
+    }
+}
+
+/// The result of processing a requested action. Encoded as an integer on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionStatus {
+    /// The action was performed.
+    Success = 1,
+    /// The action was attempted but failed.
+    Failed = 2,
+    /// The client does not support the action.
+    Unsupported = 3,
+}
+
+impl Serialize for ActionStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(*self as u8)
+    }
+}
+
+impl<'de> Deserialize<'de> for ActionStatus {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match u8::deserialize(deserializer)? {
+            1 => Ok(Self::Success),
+            2 => Ok(Self::Failed),
+            3 => Ok(Self::Unsupported),
+            other => Err(serde::de::Error::custom(format!(
+                "unknown action status: {other}"
+            ))),
+        }
+    }
+}
+
+/// The payload of a `HandshakeRequestPayloadType` message, sent by the agent to start the handshake.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HandshakeRequestPayload {
+    /// The version of the agent on the target.
+    #[serde(default)]
+    pub agent_version: String,
+    /// The actions the agent needs the client to perform before the session can start.
+    #[serde(default)]
+    pub requested_client_actions: Vec<RequestedClientAction>,
+}
+
+/// An action requested by the agent. The shape of the parameters depends on the action type.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct RequestedClientAction {
+    /// The type of the action.
+    pub action_type: ActionType,
+    /// The parameters of the action, such as a [`SessionTypeRequest`] for [`ActionType::SessionType`].
+    #[serde(default)]
+    pub action_parameters: serde_json::Value,
+}
+
+/// The parameters of an [`ActionType::SessionType`] action.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SessionTypeRequest {
+    /// The type of session, for example `Standard_Stream` or `Port`.
+    pub session_type: String,
+    /// Properties specific to the session type.
+    #[serde(default)]
+    pub properties: serde_json::Value,
+}
+
+/// The parameters of an [`ActionType::KmsEncryption`] action.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KmsEncryptionRequest {
+    /// The id of the KMS key to generate the data key under.
+    #[serde(rename = "KMSKeyId")]
+    pub kms_key_id: String,
+}
+
+/// The result of an [`ActionType::KmsEncryption`] action.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KmsEncryptionResponse {
+    /// The data key encrypted under the KMS key.
+    #[serde(rename = "KMSCipherTextKey", with = "base64_bytes")]
+    pub kms_cipher_text_key: Vec<u8>,
+    /// A hash of the encrypted data key. Not set by this client.
+    #[serde(
+        rename = "KMSCipherTextHash",
+        with = "base64_bytes",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub kms_cipher_text_hash: Vec<u8>,
+}
+
+/// The payload of an `EncChallengeRequest` message. The agent sends a random challenge encrypted with the
+/// session keys to confirm that both ends derived the same keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EncryptionChallengeRequest {
+    /// The encrypted challenge.
+    #[serde(with = "base64_bytes")]
+    pub challenge: Vec<u8>,
+}
+
+/// The payload of an `EncChallengeResponse` message, which returns the challenge re-encrypted by the client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EncryptionChallengeResponse {
+    /// The re-encrypted challenge.
+    #[serde(with = "base64_bytes")]
+    pub challenge: Vec<u8>,
+}
+
+/// The payload of a `HandshakeResponsePayloadType` message, sent by the client in reply to a
+/// [`HandshakeRequestPayload`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HandshakeResponsePayload {
+    /// The version of this client.
+    pub client_version: String,
+    /// The outcome of each requested action, in the order they were requested.
+    pub processed_client_actions: Vec<ProcessedClientAction>,
+    /// The errors that occurred while processing the requested actions.
+    #[serde(default)]
+    pub errors: Vec<String>,
+}
+
+/// The outcome of a [`RequestedClientAction`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ProcessedClientAction {
+    /// The type of the action that was requested.
+    pub action_type: ActionType,
+    /// Whether the action was performed.
+    pub action_status: ActionStatus,
+    /// The result of the action, if it produces one.
+    #[serde(default)]
+    pub action_result: serde_json::Value,
+    /// A description of why the action failed. Empty when it succeeded.
+    #[serde(default)]
+    pub error: String,
+}
+
+/// The payload of a `HandshakeCompletePayloadType` message, sent by the agent once the handshake is done.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HandshakeCompletePayload {
+    /// How long the handshake took. The agent encodes this as a Go `time.Duration`, which is a number of
+    /// nanoseconds.
+    #[serde(with = "duration_nanos", default)]
+    pub handshake_time_to_complete: Duration,
+    /// A message to show the user, if any.
+    #[serde(default)]
+    pub customer_message: String,
+}
+
+/// Byte fields are encoded as base64 strings, as Go's `encoding/json` does for `[]byte`.
+mod base64_bytes {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(encoded) => STANDARD.decode(encoded).map_err(serde::de::Error::custom),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+mod duration_nanos {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        let nanos = u64::try_from(duration.as_nanos()).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_u64(nanos)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_nanos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ActionStatus, ActionType, EncryptionChallengeRequest, HandshakeCompletePayload,
+        HandshakeRequestPayload, HandshakeResponsePayload, KmsEncryptionResponse,
+        ProcessedClientAction, SessionTypeRequest,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn deserialize_handshake_request() {
+        let payload = br#"{
+            "AgentVersion": "3.3.40.0",
+            "RequestedClientActions": [
+                {
+                    "ActionType": "SessionType",
+                    "ActionParameters": { "SessionType": "Standard_Stream", "Properties": null }
+                },
+                { "ActionType": "KMSEncryption", "ActionParameters": { "KMSKeyId": "key" } },
+                { "ActionType": "Teleport", "ActionParameters": null }
+            ]
+        }"#;
+
+        let request: HandshakeRequestPayload =
+            serde_json::from_slice(payload).expect("payload should be valid");
+
+        assert_eq!(request.agent_version, "3.3.40.0");
+        let action_types: Vec<_> = request
+            .requested_client_actions
+            .iter()
+            .map(|action| action.action_type.clone())
+            .collect();
+        assert_eq!(
+            action_types,
+            [
+                ActionType::SessionType,
+                ActionType::KmsEncryption,
+                ActionType::Unknown("Teleport".to_string())
+            ]
+        );
+
+        let session_type: SessionTypeRequest = serde_json::from_value(
+            request.requested_client_actions[0]
+                .action_parameters
+                .clone(),
+        )
+        .expect("parameters should be a session type request");
+        assert_eq!(session_type.session_type, "Standard_Stream");
+    }
+
+    #[test]
+    fn serialize_handshake_response() {
+        let response = HandshakeResponsePayload {
+            client_version: "1.0.0".to_string(),
+            processed_client_actions: vec![ProcessedClientAction {
+                action_type: ActionType::Unknown("Teleport".to_string()),
+                action_status: ActionStatus::Unsupported,
+                action_result: serde_json::Value::Null,
+                error: "Unsupported action Teleport".to_string(),
+            }],
+            errors: vec!["Unsupported action Teleport".to_string()],
+        };
+
+        let value = serde_json::to_value(&response).expect("response should serialize");
+
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "ClientVersion": "1.0.0",
+                "ProcessedClientActions": [{
+                    "ActionType": "Teleport",
+                    "ActionStatus": 3,
+                    "ActionResult": null,
+                    "Error": "Unsupported action Teleport"
+                }],
+                "Errors": ["Unsupported action Teleport"]
+            })
+        );
+    }
+
+    #[test]
+    fn deserialize_handshake_complete() {
+        let payload = br#"{"HandshakeTimeToComplete": 1500000000, "CustomerMessage": "Welcome"}"#;
+
+        let complete: HandshakeCompletePayload =
+            serde_json::from_slice(payload).expect("payload should be valid");
+
+        assert_eq!(
+            complete.handshake_time_to_complete,
+            Duration::from_millis(1500)
+        );
+        assert_eq!(complete.customer_message, "Welcome");
+    }
+
+    #[test]
+    fn encode_bytes_as_base64() {
+        let response = KmsEncryptionResponse {
+            kms_cipher_text_key: b"key".to_vec(),
+            kms_cipher_text_hash: Vec::new(),
+        };
+
+        assert_eq!(
+            serde_json::to_value(&response).expect("response should serialize"),
+            serde_json::json!({ "KMSCipherTextKey": "a2V5" })
+        );
+
+        let request: EncryptionChallengeRequest =
+            serde_json::from_slice(br#"{"Challenge": "Y2hhbGxlbmdl"}"#)
+                .expect("payload should be valid");
+
+        assert_eq!(request.challenge, b"challenge");
+    }
+}
@@ -1,20 +1,295 @@
-use super::ClientMessage;
+use super::{ClientMessage, ClientMessageRef, Flags, MessageType, PayloadType};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 use std::str;
 
 impl ClientMessage {
-    /// TODO: details about the serialization logic
+    /// Serializes the message into the binary format expected by MGS. All numeric fields are
+    /// written in big-endian order at the offsets defined on [`ClientMessage`], followed by the payload.
+    ///
+    /// As in the original implementation, the header length, payload length and payload digest are
+    /// computed from the payload at the time of serialization rather than taken from the message fields.
     ///
     /// ## Errors
     ///
-    /// TODO: document possible serialization errors
+    /// Returns [`crate::Error::MessageSerialization`] if the payload is longer than [`u32::MAX`] bytes or
+    /// if any of the fields do not fit into the space reserved for them in the header.
     pub fn serialize(&self) -> Result<Vec<u8>, crate::Error> {
-        // TODO: Implement serialization logic
-        Ok(vec![])
+        self.serialize_inner()
+            .map_err(crate::Error::MessageSerialization)
+    }
+
+    pub(super) fn serialize_inner(&self) -> Result<Vec<u8>, super::Error> {
+        let payload_length = u32::try_from(self.payload.len())?;
+        let header_length = Self::PAYLOAD_LENGTH_OFFSET;
+        let total_message_length =
+            (header_length + Self::PAYLOAD_LENGTH_LENGTH) as usize + self.payload.len();
+
+        let mut result = vec![0; total_message_length];
+
+        put_integer(
+            &mut result,
+            Self::span(Self::HEADER_OFFSET, Self::HEADER_LENGTH),
+            header_length.cast_signed(),
+        )?;
+
+        put_string(
+            &mut result,
+            Self::span(Self::MESSAGE_TYPE_OFFSET, Self::MESSAGE_TYPE_LENGTH),
+            self.message_type.as_str(),
+        )?;
+
+        put_integer(
+            &mut result,
+            Self::span(Self::SCHEMA_VERSION_OFFSET, Self::SCHEMA_VERSION_LENGTH),
+            self.schema_version.cast_signed(),
+        )?;
+
+        put_long(
+            &mut result,
+            Self::span(Self::CREATED_DATE_OFFSET, Self::CREATED_DATE_LENGTH),
+            self.create_date.timestamp_millis(),
+        )?;
+
+        put_long(
+            &mut result,
+            Self::span(Self::SEQUENCE_NUMBER_OFFSET, Self::SEQUENCE_NUMBER_LENGTH),
+            self.sequence_number,
+        )?;
+
+        put_long(
+            &mut result,
+            Self::span(Self::FLAGS_OFFSET, Self::FLAGS_LENGTH),
+            self.flags.bits().cast_signed(),
+        )?;
+
+        put_uuid(
+            &mut result,
+            Self::span(Self::MESSAGE_ID_OFFSET, Self::MESSAGE_ID_LENGTH),
+            self.message_id,
+        )?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(&self.payload);
+        let payload_digest = hasher.finalize();
+
+        put_bytes(
+            &mut result,
+            Self::span(Self::PAYLOAD_DIGEST_OFFSET, Self::PAYLOAD_DIGEST_LENGTH),
+            &payload_digest,
+        )?;
+
+        put_integer(
+            &mut result,
+            Self::span(Self::PAYLOAD_TYPE_OFFSET, Self::PAYLOAD_TYPE_LENGTH),
+            u32::from(self.payload_type).cast_signed(),
+        )?;
+
+        put_integer(
+            &mut result,
+            Self::span(Self::PAYLOAD_LENGTH_OFFSET, Self::PAYLOAD_LENGTH_LENGTH),
+            payload_length.cast_signed(),
+        )?;
+
+        put_bytes(
+            &mut result,
+            Span::with_length(Self::PAYLOAD_OFFSET as usize, self.payload.len()),
+            &self.payload,
+        )?;
+
+        Ok(result)
+    }
+
+    /// Parses a message received from MGS into an owned [`ClientMessage`]. This is the inverse of
+    /// [`ClientMessage::serialize`]. Use [`ClientMessageRef::deserialize`] to avoid copying the payload.
+    ///
+    /// ## Errors
+    ///
+    /// See [`ClientMessageRef::deserialize`].
+    pub fn deserialize(input: &[u8]) -> Result<Self, Error> {
+        ClientMessageRef::deserialize(input).map(ClientMessageRef::into_owned)
+    }
+
+    /// Convenience function for converting the offset and length constants into a [`Span`].
+    const fn span(offset: u32, length: u32) -> Span {
+        Span(offset as usize, (offset + length) as usize)
+    }
+}
+
+impl<'a> ClientMessageRef<'a> {
+    /// Parses a message received from MGS without copying. Header fields are decoded eagerly while the
+    /// payload and payload digest borrow from `input`.
+    ///
+    /// The checks performed by [`ClientMessage::validate`] are applied while parsing, so a successfully
+    /// deserialized message does not need to be validated again.
+    ///
+    /// ## Errors
+    ///
+    /// * [`Error::IncompleteMessage`] if the frame is shorter than its header or declared payload length.
+    /// * [`Error::ByteToUtf8Conversion`] if the message type is not valid UTF-8.
+    /// * [`Error::ZeroLengthHeader`] or [`Error::InvalidPayloadDigest`] if the frame fails validation.
+    pub fn deserialize(input: &'a [u8]) -> Result<Self, Error> {
+        let header_end = ClientMessage::PAYLOAD_OFFSET as usize;
+        if input.len() < header_end {
+            Err(Error::IncompleteMessage {
+                expected: header_end,
+                actual: input.len(),
+            })?;
+        }
+
+        let message_type = get_message_type(
+            input,
+            ClientMessage::span(
+                ClientMessage::MESSAGE_TYPE_OFFSET,
+                ClientMessage::MESSAGE_TYPE_LENGTH,
+            ),
+        )?;
+
+        let schema_version = get_unsigned_integer(
+            input,
+            ClientMessage::span(
+                ClientMessage::SCHEMA_VERSION_OFFSET,
+                ClientMessage::SCHEMA_VERSION_LENGTH,
+            ),
+        )?;
+
+        let create_date = get_date(
+            input,
+            ClientMessage::span(
+                ClientMessage::CREATED_DATE_OFFSET,
+                ClientMessage::CREATED_DATE_LENGTH,
+            ),
+        )?;
+
+        let sequence_number = get_long(
+            input,
+            ClientMessage::span(
+                ClientMessage::SEQUENCE_NUMBER_OFFSET,
+                ClientMessage::SEQUENCE_NUMBER_LENGTH,
+            ),
+        )?;
+
+        let flags = get_flags(
+            input,
+            ClientMessage::span(ClientMessage::FLAGS_OFFSET, ClientMessage::FLAGS_LENGTH),
+        )?;
+
+        let message_id = get_uuid(
+            input,
+            ClientMessage::span(
+                ClientMessage::MESSAGE_ID_OFFSET,
+                ClientMessage::MESSAGE_ID_LENGTH,
+            ),
+        )?;
+
+        let payload_digest = get_bytes(
+            input,
+            ClientMessage::span(
+                ClientMessage::PAYLOAD_DIGEST_OFFSET,
+                ClientMessage::PAYLOAD_DIGEST_LENGTH,
+            ),
+        )?;
+
+        let payload_type = get_unsigned_integer(
+            input,
+            ClientMessage::span(
+                ClientMessage::PAYLOAD_TYPE_OFFSET,
+                ClientMessage::PAYLOAD_TYPE_LENGTH,
+            ),
+        )?;
+
+        let payload_length = get_unsigned_integer(
+            input,
+            ClientMessage::span(
+                ClientMessage::PAYLOAD_LENGTH_OFFSET,
+                ClientMessage::PAYLOAD_LENGTH_LENGTH,
+            ),
+        )?;
+
+        let header_length = get_unsigned_integer(
+            input,
+            ClientMessage::span(ClientMessage::HEADER_OFFSET, ClientMessage::HEADER_LENGTH),
+        )?;
+
+        let is_publication_message = matches!(
+            message_type,
+            MessageType::StartPublicationMessage | MessageType::PausePublicationMessage
+        );
+
+        if header_length == 0 && !is_publication_message {
+            Err(Error::ZeroLengthHeader)?;
+        }
+
+        let payload = get_payload(input, header_length, payload_length)?;
+
+        if payload_length != 0 && !is_publication_message {
+            verify_payload_digest(payload, payload_digest)?;
+        }
+
+        Ok(Self {
+            header_length,
+            message_type,
+            schema_version,
+            create_date,
+            sequence_number,
+            flags,
+            message_id,
+            payload_digest,
+            payload_type: PayloadType::from_wire(payload_type),
+            payload_length,
+            payload,
+        })
+    }
+}
+
+/// Message types are padded with spaces when serialized, so the padding is trimmed before parsing.
+fn get_message_type(byte_array: &[u8], span: Span) -> Result<MessageType, Error> {
+    let message_type = get_str(byte_array, span)?.trim();
+
+    Ok(MessageType::from_wire(message_type))
+}
+
+/// Unknown flags are retained so that they survive re-serialization.
+fn get_flags(byte_array: &[u8], span: Span) -> Result<Flags, Error> {
+    let bits = get_long(byte_array, span)?;
+
+    Ok(Flags::from_bits_retain(bits.cast_unsigned()))
+}
+
+fn get_date(byte_array: &[u8], span: Span) -> Result<DateTime<Utc>, Error> {
+    let millis = get_long(byte_array, span)?;
+
+    DateTime::from_timestamp_millis(millis).ok_or(Error::InvalidCreatedDate(millis))
+}
+
+/// As in the original implementation, the payload starts directly after the payload length field,
+/// whose position is given by the header length.
+fn get_payload(input: &[u8], header_length: u32, payload_length: u32) -> Result<&[u8], Error> {
+    let payload_start = header_length as usize + ClientMessage::PAYLOAD_LENGTH_LENGTH as usize;
+    let payload_end = payload_start.saturating_add(payload_length as usize);
+
+    if input.len() < payload_end {
+        Err(Error::IncompleteMessage {
+            expected: payload_end,
+            actual: input.len(),
+        })?;
+    }
+
+    get_bytes(input, Span::new(payload_start, payload_end))
+}
+
+fn verify_payload_digest(payload: &[u8], payload_digest: &[u8]) -> Result<(), Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(payload);
+
+    if hasher.finalize().as_slice() != payload_digest {
+        Err(Error::InvalidPayloadDigest)?;
     }
+
+    Ok(())
 }
 
 /// putString puts a string value to a byte array starting from the specified offset.  (comment from original)
-#[allow(dead_code)]
 fn put_string(byte_array: &mut [u8], span: Span, input_string: &str) -> Result<(), Error> {
     if let Err(err) = span.fits_target(byte_array) {
         log::error!("put_string failed: Offset is invalid.");
@@ -30,7 +305,7 @@ fn put_string(byte_array: &mut [u8], span: Span, input_string: &str) -> Result<(
     byte_array
         .iter_mut()
         .skip(offset_start)
-        .take(offset_end)
+        .take(offset_end - offset_start)
         .for_each(|byte| *byte = b' ');
 
     byte_array[offset_start..(offset_start + input_string.len())]
@@ -39,15 +314,12 @@ fn put_string(byte_array: &mut [u8], span: Span, input_string: &str) -> Result<(
     Ok(())
 }
 
-#[allow(dead_code)]
 fn put_bytes(byte_array: &mut [u8], span: Span, input_bytes: &[u8]) -> Result<(), Error> {
     if let Err(err) = span.fits_target(byte_array) {
         log::error!("put_bytes failed: Offset is invalid.");
         Err(err)?;
     }
 
-    // it looks like there is a problem here -- might be possible to provide a span that causes a panic
-    // TODO: verify and fix if necessary
     if let Err(err) = span.fits_source(input_bytes.len()) {
         log::error!("put_bytes failed: Not enough space to save the input");
         Err(err)?;
@@ -60,12 +332,10 @@ fn put_bytes(byte_array: &mut [u8], span: Span, input_bytes: &[u8]) -> Result<()
 
 /// The original implementation had this function so we provide it here too for consistency's sake.
 /// It helps because the implementation only uses big-endian, so we can specify that here.
-#[allow(dead_code)]
 fn long_to_bytes(input: i64) -> [u8; 8] {
     input.to_be_bytes()
 }
 
-#[allow(dead_code)]
 fn put_long(byte_array: &mut [u8], span: Span, value: i64) -> Result<(), Error> {
     span.fits_target(byte_array)?;
 
@@ -76,7 +346,6 @@ fn put_long(byte_array: &mut [u8], span: Span, value: i64) -> Result<(), Error>
     Ok(())
 }
 
-#[allow(dead_code)]
 fn put_integer(byte_array: &mut [u8], span: Span, value: i32) -> Result<(), Error> {
     span.fits_target(byte_array)?;
 
@@ -86,11 +355,35 @@ fn put_integer(byte_array: &mut [u8], span: Span, value: i32) -> Result<(), Erro
     Ok(())
 }
 
-#[allow(dead_code)]
 fn integer_to_bytes(input: i32) -> [u8; 4] {
     input.to_be_bytes()
 }
 
+/// The original implementation writes the least significant half of the UUID first,
+/// followed by the most significant half. We do the same to stay wire-compatible.
+fn put_uuid(byte_array: &mut [u8], span: Span, input: uuid::Uuid) -> Result<(), Error> {
+    if let Err(err) = span.fits_target(byte_array) {
+        log::error!("put_uuid failed: Offset is invalid.");
+        Err(err)?;
+    }
+
+    if let Err(err) = span.fits_source(UUID_LENGTH) {
+        log::error!("put_uuid failed: Not enough space to save the input");
+        Err(err)?;
+    }
+
+    let (most_significant, least_significant) = input.as_bytes().split_at(BYTES_IN_LONG);
+
+    put_bytes(byte_array, Span::long_span(span.0), least_significant)?;
+    put_bytes(
+        byte_array,
+        Span::long_span(span.0 + BYTES_IN_LONG),
+        most_significant,
+    )?;
+
+    Ok(())
+}
+
 /// The original implementation
 #[allow(dead_code)]
 fn get_string(byte_array: &[u8], span: Span) -> Result<String, Error> {
@@ -100,7 +393,6 @@ fn get_string(byte_array: &[u8], span: Span) -> Result<String, Error> {
 const NULL_BYTE: u8 = 0x00;
 
 /// A version of the original that does not allocate.
-#[allow(dead_code)]
 fn get_str(byte_array: &[u8], span: Span) -> Result<&str, Error> {
     if let Err(err) = span.fits_target(byte_array) {
         log::error!("get_string failed: Offset is invalid.");
@@ -118,7 +410,6 @@ fn get_str(byte_array: &[u8], span: Span) -> Result<&str, Error> {
 // TODO: the get/put functions all share logic. Would be better to a single function and use generics with
 // a trait bound.
 
-#[allow(dead_code)]
 fn get_bytes(byte_array: &[u8], span: Span) -> Result<&[u8], Error> {
     if let Err(err) = span.fits_target(byte_array) {
         log::error!("get_bytes failed: Offset is invalid.");
@@ -131,7 +422,6 @@ fn get_bytes(byte_array: &[u8], span: Span) -> Result<&[u8], Error> {
     Ok(bytes)
 }
 
-#[allow(dead_code)]
 /// Converts a byte array to a long integer. The byte array must be exactly 8 bytes long.
 ///
 /// The original implementation placed length-checking logic in the `[bytes_to_long]` function,
@@ -154,6 +444,39 @@ pub fn bytes_to_long(input: [u8; BYTES_IN_LONG]) -> i64 {
     i64::from_be_bytes(input)
 }
 
+fn get_integer(byte_array: &[u8], span: Span) -> Result<i32, Error> {
+    if let Err(err) = span.fits_target(byte_array) {
+        log::error!("get_integer failed: Offset is invalid.");
+        Err(err)?;
+    }
+
+    let mut bytes = [0; BYTES_IN_INT];
+    bytes.copy_from_slice(&byte_array[span.0..span.1]);
+
+    Ok(i32::from_be_bytes(bytes))
+}
+
+/// The original implementation reads most header fields as unsigned integers, so we provide the same here.
+fn get_unsigned_integer(byte_array: &[u8], span: Span) -> Result<u32, Error> {
+    get_integer(byte_array, span).map(i32::cast_unsigned)
+}
+
+/// Reads a UUID written by [`put_uuid`], i.e. with the least significant half first.
+fn get_uuid(byte_array: &[u8], span: Span) -> Result<uuid::Uuid, Error> {
+    if let Err(err) = span.fits_target(byte_array) {
+        log::error!("get_uuid failed: Offset is invalid.");
+        Err(err)?;
+    }
+
+    let least_significant = get_long(byte_array, Span::long_span(span.0))?;
+    let most_significant = get_long(byte_array, Span::long_span(span.0 + BYTES_IN_LONG))?;
+
+    Ok(uuid::Uuid::from_u64_pair(
+        most_significant.cast_unsigned(),
+        least_significant.cast_unsigned(),
+    ))
+}
+
 pub fn trim_bytes(data: &[u8], byte: u8) -> &[u8] {
     let start = data.iter().position(|&b| b != byte).unwrap_or(data.len());
     let end = data.iter().rposition(|&b| b != byte).map_or(0, |i| i + 1);
@@ -162,6 +485,7 @@ pub fn trim_bytes(data: &[u8], byte: u8) -> &[u8] {
 
 const BYTES_IN_LONG: usize = (u64::BITS / 8) as usize;
 const BYTES_IN_INT: usize = (i32::BITS / 8) as usize;
+const UUID_LENGTH: usize = 2 * BYTES_IN_LONG;
 
 /// Represents a contiguous span of bytes in a byte array. Used for indicating
 /// the offset at which to inject a data type into an array.
@@ -203,19 +527,13 @@ impl Span {
         }
     }
 
+    /// Whether the span lies within `byte_array`. A zero length span fits anywhere up to the end of the
+    /// array, so that an empty payload can be written or read at the end of a message.
     pub fn fits_target(&self, byte_array: &[u8]) -> Result<(), Error> {
-        // A zero length span can fit any target
-        if self.len() == 0 {
-            return Ok(());
-        }
-
         let Span(offset_start, offset_end) = *self;
         let byte_array_length = byte_array.len();
 
-        if byte_array_length == 0
-            || offset_start > byte_array_length
-            || offset_end > byte_array_length
-        {
+        if offset_start > byte_array_length || offset_end > byte_array_length {
             Err(Error::OffsetOutOfBounds)?;
         }
 
@@ -241,6 +559,41 @@ pub enum Error {
     /// TODO: document
     #[error("Attempted to extract a string from a byte array that is not valid UTF-8: {0}")]
     ByteToUtf8Conversion(#[from] std::str::Utf8Error),
+
+    /// The frame ended before all of the fields it declares could be read.
+    #[error("Message is truncated: expected at least {expected} bytes but received {actual}.")]
+    IncompleteMessage {
+        /// The number of bytes required to read the message
+        expected: usize,
+        /// The number of bytes that were received
+        actual: usize,
+    },
+
+    /// A flag payload contained a value that does not correspond to any [`PayloadTypeFlag`].
+    ///
+    /// [`PayloadTypeFlag`]: super::PayloadTypeFlag
+    #[error("Unknown payload type flag: {0}.")]
+    UnknownPayloadTypeFlag(u32),
+
+    /// A flag payload was not a 4 byte integer.
+    #[error("Flag payload must be 4 bytes long. Found {0} bytes.")]
+    InvalidFlagLength(usize),
+
+    /// An exit code payload did not contain a decimal integer.
+    #[error("Exit code payload '{0}' is not an integer.")]
+    InvalidExitCode(String),
+
+    /// The created date field could not be represented as a UTC timestamp.
+    #[error("Created date '{0}' is not a valid timestamp.")]
+    InvalidCreatedDate(i64),
+
+    /// The header length field was zero.
+    #[error("HeaderLength cannot be zero")]
+    ZeroLengthHeader,
+
+    /// The SHA-256 digest of the payload did not match the payload digest field.
+    #[error("payload Hash is not valid")]
+    InvalidPayloadDigest,
 }
 
 #[cfg(test)]
@@ -430,7 +783,15 @@ mod test {
                 byte_array: default_byte_buffer_generator(),
                 span: super::Span(1, 7),
                 input: "hello",
-                expected_buffer: b"\0hello  ",
+                expected_buffer: b"\0hello \0",
+                expected_output: Ok(()),
+            })
+            .add_test_case(TestParams {
+                name: "adjacent fields are preserved",
+                byte_array: vec![0xaa; 8],
+                span: super::Span(2, 5),
+                input: "hi",
+                expected_buffer: &[0xaa, 0xaa, b'h', b'i', b' ', 0xaa, 0xaa, 0xaa],
                 expected_output: Ok(()),
             })
             .add_test_case(TestParams {
@@ -453,11 +814,9 @@ mod test {
                 &mut TestFunc::Setter(&mut super::put_string),
                 |byte_array, span, input, name| {
                     let super::Span(offset_start, offset_end) = span;
-                    let trailing = byte_array[(offset_start + input.len())
-                        ..(offset_start + input.len() + offset_end - input.len())]
-                        .to_vec();
+                    let trailing = byte_array[(offset_start + input.len())..offset_end].to_vec();
 
-                    let trailing_should_be = " ".repeat(offset_end - input.len());
+                    let trailing_should_be = " ".repeat(span.len() - input.len());
 
                     assert_eq!(
                         trailing,
@@ -503,6 +862,22 @@ mod test {
                 expected_buffer: &[],
                 expected_output: Err(super::Error::OffsetOutOfBounds),
             })
+            .add_test_case(TestParams {
+                name: "Empty input at the end of the buffer",
+                byte_array: default_byte_buffer_generator(),
+                span: super::Span::new(8, 8),
+                input: [].as_ref(),
+                expected_buffer: &[0x00; 8],
+                expected_output: Ok(()),
+            })
+            .add_test_case(TestParams {
+                name: "Empty input past the end of the buffer",
+                byte_array: default_byte_buffer_generator(),
+                span: super::Span::new(9, 9),
+                input: [].as_ref(),
+                expected_buffer: &[0x00; 8],
+                expected_output: Err(super::Error::OffsetOutOfBounds),
+            })
             .execute(
                 &mut TestFunc::Setter(&mut super::put_bytes),
                 |_, _, _, _| {},
@@ -741,6 +1116,282 @@ mod test {
             .execute(&mut TestFunc::Getter(&mut super::get_long), |_, _, _, _| {});
     }
 
+    #[test]
+    fn serialize_client_message() {
+        use crate::message::{ClientMessage, Flags, PayloadType};
+        use sha2::{Digest, Sha256};
+
+        let payload = b"payload".to_vec();
+        let message = ClientMessage::builder()
+            .with_flags(Flags::SYN)
+            .with_sequence_number(7)
+            .input_stream_data(PayloadType::Output, payload.clone())
+            .expect("message should be valid")
+            .build();
+
+        let bytes = message.serialize().expect("serialization should succeed");
+
+        let offset = |offset: u32| offset as usize;
+
+        assert_eq!(
+            bytes.len(),
+            offset(ClientMessage::PAYLOAD_OFFSET) + payload.len(),
+            "message should be header followed by payload"
+        );
+        assert_eq!(
+            super::get_bytes(&bytes, Span::int_span(offset(ClientMessage::HEADER_OFFSET))),
+            Ok(ClientMessage::PAYLOAD_LENGTH_OFFSET
+                .to_be_bytes()
+                .as_slice())
+        );
+        assert_eq!(
+            super::get_str(
+                &bytes,
+                Span::with_length(offset(ClientMessage::MESSAGE_TYPE_OFFSET), 32)
+            )
+            .map(str::trim_end),
+            Ok("input_stream_data")
+        );
+        assert_eq!(
+            super::get_bytes(
+                &bytes,
+                Span::int_span(offset(ClientMessage::SCHEMA_VERSION_OFFSET))
+            ),
+            Ok(1_u32.to_be_bytes().as_slice())
+        );
+        assert_eq!(
+            super::get_long(
+                &bytes,
+                Span::long_span(offset(ClientMessage::CREATED_DATE_OFFSET))
+            ),
+            Ok(message.create_date.timestamp_millis())
+        );
+        assert_eq!(
+            super::get_long(
+                &bytes,
+                Span::long_span(offset(ClientMessage::SEQUENCE_NUMBER_OFFSET))
+            ),
+            Ok(7)
+        );
+        assert_eq!(
+            super::get_long(&bytes, Span::long_span(offset(ClientMessage::FLAGS_OFFSET))),
+            Ok(1)
+        );
+
+        let message_id = message.message_id.as_bytes();
+        let message_id_offset = offset(ClientMessage::MESSAGE_ID_OFFSET);
+        assert_eq!(
+            super::get_bytes(&bytes, Span::with_length(message_id_offset, 16)),
+            Ok([&message_id[8..], &message_id[..8]].concat().as_slice()),
+            "message id should be written least significant half first"
+        );
+
+        let mut hasher = Sha256::new();
+        hasher.update(&payload);
+        assert_eq!(
+            super::get_bytes(
+                &bytes,
+                Span::with_length(offset(ClientMessage::PAYLOAD_DIGEST_OFFSET), 32)
+            ),
+            Ok(hasher.finalize().as_slice())
+        );
+        assert_eq!(
+            super::get_bytes(
+                &bytes,
+                Span::int_span(offset(ClientMessage::PAYLOAD_TYPE_OFFSET))
+            ),
+            Ok(1_u32.to_be_bytes().as_slice())
+        );
+        assert_eq!(
+            super::get_bytes(
+                &bytes,
+                Span::int_span(offset(ClientMessage::PAYLOAD_LENGTH_OFFSET))
+            ),
+            Ok(7_u32.to_be_bytes().as_slice())
+        );
+        assert_eq!(
+            &bytes[offset(ClientMessage::PAYLOAD_OFFSET)..],
+            payload.as_slice()
+        );
+    }
+
+    fn serialized_test_message() -> (crate::message::ClientMessage, Vec<u8>) {
+        use crate::message::{ClientMessage, Flags, PayloadType};
+
+        let message = ClientMessage::builder()
+            .with_flags(Flags::FIN)
+            .with_sequence_number(12)
+            .output_stream_data(PayloadType::StdErr, b"payload".to_vec())
+            .expect("message should be valid")
+            .build();
+        let bytes = message.serialize().expect("serialization should succeed");
+
+        (message, bytes)
+    }
+
+    #[test]
+    fn deserialize_client_message() {
+        use crate::message::ClientMessage;
+
+        let (message, bytes) = serialized_test_message();
+
+        let result = ClientMessage::deserialize(&bytes).expect("message should be valid");
+
+        assert_eq!(result.header_length, ClientMessage::PAYLOAD_LENGTH_OFFSET);
+        assert_eq!(result.message_type(), message.message_type());
+        assert_eq!(result.schema_version(), message.schema_version());
+        assert_eq!(
+            result.create_date().timestamp_millis(),
+            message.create_date().timestamp_millis()
+        );
+        assert_eq!(result.sequence_number(), message.sequence_number());
+        assert_eq!(result.flags(), message.flags());
+        assert_eq!(result.message_id(), message.message_id());
+        assert_eq!(result.payload_digest, message.payload_digest);
+        assert_eq!(result.payload_type(), message.payload_type());
+        assert_eq!(result.payload_length, message.payload_length);
+        assert_eq!(result.payload(), message.payload());
+        result
+            .validate()
+            .expect("deserialized message should be valid");
+    }
+
+    #[test]
+    fn deserialize_borrows_payload() {
+        use crate::message::{ClientMessage, ClientMessageRef};
+
+        let (message, bytes) = serialized_test_message();
+
+        let result = ClientMessageRef::deserialize(&bytes).expect("message should be valid");
+
+        assert_eq!(result.payload(), message.payload());
+        assert!(
+            std::ptr::eq(
+                result.payload().as_ptr(),
+                bytes[ClientMessage::PAYLOAD_OFFSET as usize..].as_ptr()
+            ),
+            "payload should point into the receive buffer"
+        );
+        assert_eq!(result.message_id(), message.message_id());
+        assert_eq!(result.sequence_number(), message.sequence_number());
+
+        let owned = result.into_owned();
+        assert_eq!(owned.payload(), message.payload());
+        assert_eq!(owned.payload_digest, message.payload_digest);
+    }
+
+    #[test]
+    fn deserialize_truncated_client_message() {
+        use crate::message::ClientMessage;
+
+        let (_, bytes) = serialized_test_message();
+
+        let result = ClientMessage::deserialize(&bytes[..10]);
+        assert!(matches!(
+            result,
+            Err(super::Error::IncompleteMessage { actual: 10, .. })
+        ));
+
+        let result = ClientMessage::deserialize(&bytes[..bytes.len() - 1]);
+        assert!(matches!(
+            result,
+            Err(super::Error::IncompleteMessage { expected, .. }) if expected == bytes.len()
+        ));
+
+        let mut bytes = bytes;
+        bytes[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let result = ClientMessage::deserialize(&bytes);
+        assert!(matches!(
+            result,
+            Err(super::Error::IncompleteMessage { .. })
+        ));
+    }
+
+    #[test]
+    fn deserialize_unknown_types() {
+        use crate::message::{ClientMessage, MessageType, PayloadType};
+
+        let (_, bytes) = serialized_test_message();
+
+        let mut unknown = bytes.clone();
+        super::put_string(
+            &mut unknown,
+            Span::with_length(ClientMessage::MESSAGE_TYPE_OFFSET as usize, 32),
+            "interactive_shell_data",
+        )
+        .expect("message type should fit");
+        super::put_integer(
+            &mut unknown,
+            Span::int_span(ClientMessage::PAYLOAD_TYPE_OFFSET as usize),
+            13,
+        )
+        .expect("payload type should fit");
+
+        let message = ClientMessage::deserialize(&unknown).expect("message should be valid");
+
+        assert_eq!(
+            message.message_type(),
+            &MessageType::Unknown("interactive_shell_data".to_string())
+        );
+        assert_eq!(message.payload_type(), PayloadType::Unknown(13));
+        assert_eq!(
+            message.serialize().expect("serialization should succeed"),
+            unknown
+        );
+    }
+
+    #[test]
+    fn retain_unknown_flags() {
+        use crate::message::{ClientMessage, Flags};
+
+        let (_, mut bytes) = serialized_test_message();
+        super::put_long(
+            &mut bytes,
+            Span::with_length(ClientMessage::FLAGS_OFFSET as usize, 8),
+            0b1010,
+        )
+        .expect("flags should fit");
+
+        let message = ClientMessage::deserialize(&bytes).expect("message should be valid");
+
+        assert!(message.flags().contains(Flags::FIN));
+        assert_eq!(message.flags().bits(), 0b1010);
+        assert_eq!(
+            message.serialize().expect("serialization should succeed"),
+            bytes
+        );
+    }
+
+    #[test]
+    fn deserialize_invalid_client_message() {
+        use crate::message::ClientMessage;
+
+        let (_, bytes) = serialized_test_message();
+        let message_type_offset = ClientMessage::MESSAGE_TYPE_OFFSET as usize;
+
+        let mut invalid_utf8 = bytes.clone();
+        invalid_utf8[message_type_offset] = 0xff;
+        assert!(matches!(
+            ClientMessage::deserialize(&invalid_utf8),
+            Err(super::Error::ByteToUtf8Conversion(_))
+        ));
+
+        let mut zero_header = bytes.clone();
+        super::put_integer(&mut zero_header, Span::int_span(0), 0)
+            .expect("header length should fit");
+        assert_eq!(
+            ClientMessage::deserialize(&zero_header).map(|_| ()),
+            Err(super::Error::ZeroLengthHeader)
+        );
+
+        let mut tampered_payload = bytes;
+        *tampered_payload.last_mut().expect("payload is not empty") = b'!';
+        assert_eq!(
+            ClientMessage::deserialize(&tampered_payload).map(|_| ()),
+            Err(super::Error::InvalidPayloadDigest)
+        );
+    }
+
     #[test]
     fn span_with_length() {
         let test_cases = [
@@ -773,24 +1424,26 @@ mod test {
         }
     }
 
-    // #[test]
-    // fn span_fits_target() {
-    //     let test_cases = [
-    //         (Span(0, 0), Ok(())),
-    //         (Span(0, 8), Ok(())),
-    //         (Span(0, 9), Err(super::Error::OffsetOutOfBounds)),
-    //         (Span(1, 8), Err(super::Error::OffsetOutOfBounds)),
-    //         (Span(1, 9), Err(super::Error::OffsetOutOfBounds)),
-    //     ];
+    #[test]
+    fn span_fits_target() {
+        let test_cases = [
+            (Span(0, 0), Ok(())),
+            (Span(0, 8), Ok(())),
+            (Span(1, 8), Ok(())),
+            (Span(8, 8), Ok(())),
+            (Span(0, 9), Err(super::Error::OffsetOutOfBounds)),
+            (Span(1, 9), Err(super::Error::OffsetOutOfBounds)),
+            (Span(9, 9), Err(super::Error::OffsetOutOfBounds)),
+        ];
 
-    //     for (span, expected) in test_cases {
-    //         let result = span.fits_target(&[0; 8]);
-    //         assert_eq!(
-    //             result, expected,
-    //             "Expected result mismatch for span: {span:?}"
-    //         );
-    //     }
-    // }
+        for (span, expected) in test_cases {
+            let result = span.fits_target(&[0; 8]);
+            assert_eq!(
+                result, expected,
+                "Expected result mismatch for span: {span:?}"
+            );
+        }
+    }
 
     // #[test]
     // fn span_fits_source() {
@@ -5,25 +5,32 @@
 //! although input validation logic has been extracted to the main session-manager-plugin crate.
 
 use session_util::DisplayMode;
-use std::collections::HashMap;
+use std::{collections::HashMap, convert::Infallible, io::Write, mem, ops::ControlFlow, sync::Arc};
+use terminal_size::TerminalSizeWatcher;
+use tokio::task::{JoinError, JoinSet};
 use uuid::Uuid;
 
 use crate::{
-    data_channel::{DataChannel, DefaultDataChannel},
+    config,
+    data_channel::{DataChannel, DataChannelEvent, DefaultDataChannel},
     error::Error,
+    message::{ChannelClosed, PayloadType, PayloadTypeFlag},
     retry::RepeatableExponentialRetryer,
 };
 
 mod session_util;
+pub mod terminal_size;
 
 /// A session represents a connection to a target.
 #[derive(Debug)]
 #[allow(dead_code)] // TODO: remove this once the struct is fully implemented
+#[allow(clippy::struct_field_names)] // field names match the original implementation
 pub struct Session<Channel>
 where
     Channel: DataChannel,
 {
-    data_channel: Channel,
+    /// Shared with the tasks which run alongside the session.
+    data_channel: Arc<Channel>,
     session_id: String,
     stream_url: String,
     token_value: String,
@@ -36,35 +43,217 @@ where
     session_type: String,
     session_properties: HashMap<String, String>,
     display_mode: DisplayMode,
+    terminal_size: TerminalSizeWatcher,
+    /// Set once a shell session has started, until `execute` starts keeping the terminal size up to date.
+    watch_terminal_size: bool,
+    exit_code: Option<i32>,
+}
+
+/// The result of a session that ended without error.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SessionOutcome {
+    /// The exit code of the remote command. Only reported by the agent for non-interactive command
+    /// sessions.
+    pub exit_code: Option<i32>,
 }
 
 impl<Channel> Session<Channel>
 where
     Channel: DataChannel,
 {
-    /// Execute the session.
+    /// Open the data channel and handle the events it produces until the session ends. The session ends
+    /// when the agent closes the channel, or when the connection ends after the agent has reported the
+    /// exit code of the remote command. Once a shell session has started, the agent is also told whenever
+    /// the size of the terminal changes.
+    ///
+    /// Unacknowledged messages are resent by a task which runs for as long as the session. If the agent
+    /// stops acknowledging them, or the task fails, the session is terminated.
     ///
     /// ## Errors
     ///
-    /// TODO: Fill this in with actual error conditions
-    #[allow(clippy::unused_async)] // TODO: Remove this once the function is fully implemented
-    pub async fn execute(&self) -> Result<(), Error> {
+    /// * [`Error::DataChannelOpen`] if the data channel cannot be opened.
+    /// * [`Error::StreamMessageResendTimeout`] if a message was resent too often without being
+    ///   acknowledged.
+    /// * [`Error::SessionTask`] if a task which runs alongside the session panicked.
+    /// * The error which ended the data channel connection before the session ended.
+    /// * An error if an event cannot be handled, for example if output cannot be written.
+    /// * An error if the terminal size cannot be sent.
+    pub async fn execute(&mut self) -> Result<SessionOutcome, Error>
+    where
+        Channel: 'static,
+    {
         println!("\nStarting session with SessionId: {}\n", self.session_id);
 
-        Ok(())
+        self.open_data_channel().await?;
+
+        // Dropping the sets when the session ends aborts the tasks.
+        let mut resend_scheduler = JoinSet::new();
+        let data_channel = Arc::clone(&self.data_channel);
+        resend_scheduler
+            .spawn(async move { data_channel.resend_stream_data_message_scheduler().await });
+        let mut terminal_size_watcher = JoinSet::new();
+
+        loop {
+            let received = tokio::select! {
+                received = self.data_channel.receive_events() => received,
+                Some(joined) = terminal_size_watcher.join_next() => {
+                    return Err(task_error(joined));
+                }
+                Some(joined) = resend_scheduler.join_next() => {
+                    return Err(self.terminate(task_error(joined)).await);
+                }
+            };
+
+            let events = match received {
+                Ok(events) => events,
+                Err(Error::WebsocketClosed) if self.exit_code.is_some() => {
+                    log::debug!("Connection closed after the exit code was received");
+                    return Ok(self.outcome());
+                }
+                Err(e) => return Err(e),
+            };
+
+            for event in events {
+                if let ControlFlow::Break(outcome) = self.handle_data_channel_event(event).await? {
+                    return Ok(outcome);
+                }
+            }
+
+            if mem::take(&mut self.watch_terminal_size) {
+                let mut watcher = mem::take(&mut self.terminal_size);
+                let data_channel = Arc::clone(&self.data_channel);
+                terminal_size_watcher.spawn(async move { watcher.watch(&*data_channel).await });
+            }
+        }
+    }
+
+    /// End the session because the agent stopped acknowledging messages or the resend scheduler failed,
+    /// and return `error`. Ported from `handleStreamMessageResendTimeout` in the original implementation,
+    /// which also terminates the session with the SSM API.
+    async fn terminate(&self, error: Error) -> Error {
+        log::error!("Terminating session {}: {error}", self.session_id);
+
+        // TODO: call the TerminateSession API once the SDK client is available
+        if let Err(e) = self.data_channel.close().await {
+            log::error!("Failed to close the data channel: {e}");
+        }
+
+        error
     }
 
     /// Open a data channel for the session.
     ///
     /// ## Errors
-    /// TODO: Fill this in with actual error conditions
-    pub fn open_data_channel(&self) -> Result<(), Error> {
+    ///
+    /// Returns [`Error::DataChannelOpen`] if the data channel cannot be opened.
+    pub async fn open_data_channel(&self) -> Result<(), Error> {
         println!(
             "\nOpening data channel for session with SessionId: {}\n",
             self.session_id
         );
 
-        Ok(())
+        // TODO: retry with `retry_params` as the original implementation does
+        self.data_channel
+            .open()
+            .await
+            .map_err(|e| Error::DataChannelOpen(Box::new(e)))
+    }
+
+    /// Handle an event produced by the data channel. Returns [`ControlFlow::Break`] once the session
+    /// has ended, which only happens when the agent closes the channel. A network failure surfaces as an
+    /// error from the data channel instead, so the two can be told apart.
+    async fn handle_data_channel_event(
+        &mut self,
+        event: DataChannelEvent,
+    ) -> Result<ControlFlow<SessionOutcome>, Error> {
+        match event {
+            // TODO: hand stream data to the session type handler once it exists
+            DataChannelEvent::StreamData {
+                payload_type: payload_type @ (PayloadType::Output | PayloadType::StdErr),
+                payload,
+            } => {
+                self.display_mode
+                    .display_message(payload_type, &payload)
+                    .map_err(Error::WriteOutput)?;
+
+                Ok(ControlFlow::Continue(()))
+            }
+            DataChannelEvent::StreamData { payload_type, .. } => {
+                log::trace!("Ignoring stream data with payload type {payload_type:?}");
+
+                Ok(ControlFlow::Continue(()))
+            }
+            DataChannelEvent::ExitCode(exit_code) => {
+                self.exit_code = Some(exit_code);
+
+                Ok(ControlFlow::Continue(()))
+            }
+            DataChannelEvent::Flag(flag) => {
+                // TODO: hand flags to the port session handler once it exists
+                if flag == PayloadTypeFlag::ConnectToPortError {
+                    println!("\nConnection to destination port failed, check SSM Agent logs.");
+                }
+
+                Ok(ControlFlow::Continue(()))
+            }
+            // TODO: select the session type handler from the negotiated session type
+            DataChannelEvent::HandshakeComplete {
+                session_type,
+                customer_message,
+            } => {
+                if !customer_message.is_empty() {
+                    println!("{customer_message}");
+                }
+
+                // `execute` keeps the size up to date from here on.
+                if session_type.is_some_and(|s| s.session_type == config::SHELL_PLUGIN_NAME) {
+                    self.terminal_size
+                        .send_if_changed(&*self.data_channel)
+                        .await?;
+                    self.watch_terminal_size = true;
+                }
+
+                Ok(ControlFlow::Continue(()))
+            }
+            DataChannelEvent::ChannelClosed(channel_closed) => {
+                print!("{}", self.channel_closed_output(&channel_closed));
+                self.data_channel.close().await?;
+
+                Ok(ControlFlow::Break(self.outcome()))
+            }
+        }
+    }
+
+    fn outcome(&self) -> SessionOutcome {
+        SessionOutcome {
+            exit_code: self.exit_code,
+        }
+    }
+
+    /// Format the message shown to the user when the agent closes the channel, matching the output of
+    /// the original implementation.
+    fn channel_closed_output(&self, channel_closed: &ChannelClosed) -> String {
+        if channel_closed.output.is_empty() {
+            format!(
+                "\n\nExiting session with sessionId: {}.\n\n",
+                self.session_id
+            )
+        } else {
+            format!(
+                "\n\nSessionId: {} : {}\n\n",
+                self.session_id, channel_closed.output
+            )
+        }
+    }
+}
+
+/// The error which ended a task that runs alongside the session. The tasks only end on error, or if
+/// they panic.
+fn task_error(joined: Result<Result<Infallible, Error>, JoinError>) -> Error {
+    match joined {
+        Ok(Err(e)) => e,
+        Err(e) => Error::SessionTask(e),
     }
 }
 
@@ -83,6 +272,7 @@ where
     target_id: String,
     session_type: String,
     session_properties: HashMap<String, String>,
+    display_mode: Option<DisplayMode>,
 }
 
 impl SessionBuilder<DefaultDataChannel> {
@@ -141,6 +331,18 @@ where
         self
     }
 
+    /// Set where the remote command's output is written. `Output` data is written to `stdout` and `StdErr`
+    /// data to `stderr`. Defaults to the process's standard output and standard error.
+    #[must_use]
+    pub fn with_output_writers(
+        mut self,
+        stdout: impl Write + Send + 'static,
+        stderr: impl Write + Send + 'static,
+    ) -> Self {
+        self.display_mode = Some(DisplayMode::with_writers(stdout, stderr));
+        self
+    }
+
     /// Set a custom data channel. This allows for custom implementations of the data channel.
     pub fn with_data_channel<C>(self, data_channel: C) -> SessionBuilder<C>
     where
@@ -156,6 +358,7 @@ where
             target_id: self.target_id,
             session_type: self.session_type,
             session_properties: self.session_properties,
+            display_mode: self.display_mode,
         }
     }
 
@@ -172,58 +375,713 @@ where
             target_id: self.target_id,
             session_type: self.session_type,
             session_properties: self.session_properties,
-            display_mode: DisplayMode::new(), // Note: consider making DisplayMode generic to allow for custom implementations
+            display_mode: self.display_mode.unwrap_or_else(DisplayMode::new),
             retry_params: RepeatableExponentialRetryer::default(),
-            data_channel: self.data_channel,
+            data_channel: Arc::new(self.data_channel),
+            terminal_size: TerminalSizeWatcher::new(),
+            watch_terminal_size: false,
+            exit_code: None,
         }
     }
 }
 
-// todo: port these two tests
-// func TestExecuteAndStreamMessageResendTimesOut(t *testing.T) {
-//     sessionMock := &Session{}
-//     sessionMock.DataChannel = mockDataChannel
-//     SetupMockActions()
-//     mockDataChannel.On("Open", mock.Anything).Return(nil)
-
-//     isStreamMessageResendTimeout := make(chan bool, 1)
-//     mockDataChannel.On("IsStreamMessageResendTimeout").Return(isStreamMessageResendTimeout)
-
-//     var wg sync.WaitGroup
-//     wg.Add(1)
-//     handleStreamMessageResendTimeout = func(session *Session, log log.T) {
-//         time.Sleep(10 * time.Millisecond)
-//         isStreamMessageResendTimeout <- true
-//         wg.Done()
-//         return
-//     }
-
-//     isSessionTypeSetMock := make(chan bool, 1)
-//     isSessionTypeSetMock <- true
-//     mockDataChannel.On("IsSessionTypeSet").Return(isSessionTypeSetMock)
-//     mockDataChannel.On("GetSessionType").Return("Standard_Stream")
-//     mockDataChannel.On("GetSessionProperties").Return("SessionProperties")
-
-//     setSessionHandlersWithSessionType = func(session *Session, log log.T) error {
-//         return nil
-//     }
-
-//     var err error
-//     go func() {
-//         err = sessionMock.Execute(logger)
-//         time.Sleep(200 * time.Millisecond)
-//     }()
-//     wg.Wait()
-//     assert.Nil(t, err)
-// }
-
-// func SetupMockActions() {
-//     mockDataChannel.On("Initialize", mock.Anything, mock.Anything, mock.Anything, mock.Anything, mock.Anything).Return()
-//     mockDataChannel.On("SetWebsocket", mock.Anything, mock.Anything, mock.Anything).Return()
-//     mockDataChannel.On("GetWsChannel").Return(mockWsChannel)
-//     mockDataChannel.On("RegisterOutputStreamHandler", mock.Anything, mock.Anything)
-//     mockDataChannel.On("ResendStreamDataMessageScheduler", mock.Anything).Return(nil)
-
-//     mockWsChannel.On("SetOnMessage", mock.Anything)
-//     mockWsChannel.On("SetOnError", mock.Anything)
-// }
+#[cfg(test)]
+mod test {
+    use super::{
+        Session, SessionBuilder, SessionOutcome, TerminalSizeWatcher,
+        terminal_size::{RESIZE_POLL_INTERVAL, TestTerminal},
+    };
+    use crate::{
+        config,
+        data_channel::{DataChannelEvent, DefaultDataChannel, MockDataChannel},
+        message::{ChannelClosed, ClientMessage, PayloadType, SessionTypeRequest, SizeData},
+        websocket_channel::{
+            BINARY_MESSAGE, IncomingMessages, LoopbackWebsocketChannel, WebsocketChannel,
+        },
+    };
+    use std::{
+        io::Write,
+        ops::ControlFlow,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    const SESSION_ID: &str = "session-id";
+
+    #[tokio::test]
+    async fn execute_reports_exit_code() {
+        let mut data_channel = MockDataChannel::new();
+        let mut sequence = mockall::Sequence::new();
+        data_channel
+            .expect_open()
+            .once()
+            .in_sequence(&mut sequence)
+            .returning(|| Box::pin(async { Ok(()) }));
+        data_channel
+            .expect_receive_events()
+            .once()
+            .in_sequence(&mut sequence)
+            .returning(|| Box::pin(async { Ok(vec![DataChannelEvent::ExitCode(42)]) }));
+        data_channel
+            .expect_receive_events()
+            .once()
+            .in_sequence(&mut sequence)
+            .returning(|| {
+                Box::pin(async {
+                    Ok(vec![DataChannelEvent::ChannelClosed(
+                        ChannelClosed::default(),
+                    )])
+                })
+            });
+        data_channel
+            .expect_close()
+            .once()
+            .in_sequence(&mut sequence)
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        let outcome = session.execute().await.expect("Session should end.");
+
+        assert_eq!(
+            outcome,
+            SessionOutcome {
+                exit_code: Some(42)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_ends_when_connection_closes_after_exit_code() {
+        let mut data_channel = MockDataChannel::new();
+        let mut sequence = mockall::Sequence::new();
+        data_channel
+            .expect_open()
+            .once()
+            .in_sequence(&mut sequence)
+            .returning(|| Box::pin(async { Ok(()) }));
+        data_channel
+            .expect_receive_events()
+            .once()
+            .in_sequence(&mut sequence)
+            .returning(|| Box::pin(async { Ok(vec![DataChannelEvent::ExitCode(42)]) }));
+        data_channel
+            .expect_receive_events()
+            .once()
+            .in_sequence(&mut sequence)
+            .returning(|| Box::pin(async { Err(crate::Error::WebsocketClosed) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        let outcome = session.execute().await.expect("Session should end.");
+
+        assert_eq!(
+            outcome,
+            SessionOutcome {
+                exit_code: Some(42)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_terminates_session_on_resend_timeout() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        data_channel
+            .expect_receive_events()
+            .returning(|| Box::pin(std::future::pending()));
+        data_channel
+            .expect_resend_stream_data_message_scheduler()
+            .once()
+            .returning(|| Box::pin(async { Err(crate::Error::StreamMessageResendTimeout(7)) }));
+        data_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        assert!(matches!(
+            session.execute().await,
+            Err(crate::Error::StreamMessageResendTimeout(7))
+        ));
+    }
+
+    #[tokio::test]
+    async fn execute_terminates_session_if_resend_scheduler_panics() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        data_channel
+            .expect_receive_events()
+            .returning(|| Box::pin(std::future::pending()));
+        data_channel
+            .expect_resend_stream_data_message_scheduler()
+            .once()
+            .returning(|| Box::pin(async { panic!("Scheduler failed.") }));
+        data_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        assert!(matches!(
+            session.execute().await,
+            Err(crate::Error::SessionTask(e)) if e.is_panic()
+        ));
+    }
+
+    #[tokio::test]
+    async fn execute_ends_when_agent_closes_channel() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        data_channel.expect_receive_events().once().returning(|| {
+            Box::pin(async {
+                Ok(vec![DataChannelEvent::ChannelClosed(ChannelClosed {
+                    output: "Session terminated".to_string(),
+                    ..Default::default()
+                })])
+            })
+        });
+        data_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        let outcome = session.execute().await.expect("Session should end.");
+
+        assert_eq!(outcome, SessionOutcome::default());
+    }
+
+    #[tokio::test]
+    async fn execute_writes_stderr_separately() {
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        data_channel.expect_receive_events().once().returning(|| {
+            Box::pin(async {
+                Ok([
+                    (PayloadType::Output, b"out ".as_slice()),
+                    (PayloadType::StdErr, b"err"),
+                    (PayloadType::Output, b"put"),
+                ]
+                .into_iter()
+                .map(|(payload_type, payload)| DataChannelEvent::StreamData {
+                    payload_type,
+                    payload: payload.to_vec(),
+                })
+                .chain([DataChannelEvent::ChannelClosed(ChannelClosed::default())])
+                .collect())
+            })
+        });
+        data_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .with_output_writers(stdout.clone(), stderr.clone())
+            .build();
+
+        session.execute().await.expect("Session should end.");
+
+        assert_eq!(*stdout.0.lock().unwrap(), b"out put");
+        assert_eq!(*stderr.0.lock().unwrap(), b"err");
+    }
+
+    #[tokio::test]
+    async fn execute_fails_if_data_channel_fails() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        data_channel.expect_receive_events().once().returning(|| {
+            Box::pin(async {
+                Err(crate::Error::InvalidClientMessage(
+                    crate::message::Error::ZeroLengthHeader,
+                ))
+            })
+        });
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        assert!(matches!(
+            session.execute().await,
+            Err(crate::Error::InvalidClientMessage(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn execute_fails_if_connection_ends_early() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_open()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+        data_channel
+            .expect_receive_events()
+            .once()
+            .returning(|| Box::pin(async { Err(crate::Error::WebsocketClosed) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        assert!(matches!(
+            session.execute().await,
+            Err(crate::Error::WebsocketClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn channel_closed_ends_session() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+        let channel_closed = ChannelClosed {
+            output: "Session terminated".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            session.channel_closed_output(&channel_closed),
+            "\n\nSessionId: session-id : Session terminated\n\n"
+        );
+        assert_eq!(
+            session.channel_closed_output(&ChannelClosed::default()),
+            "\n\nExiting session with sessionId: session-id.\n\n"
+        );
+
+        let result = session
+            .handle_data_channel_event(DataChannelEvent::ChannelClosed(channel_closed))
+            .await
+            .expect("Channel closed event should be handled.");
+
+        assert_eq!(result, ControlFlow::Break(SessionOutcome::default()));
+    }
+
+    #[tokio::test]
+    async fn shell_session_sends_terminal_size_after_handshake() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_send_input_data_message()
+            .once()
+            .withf(|payload_type, _| *payload_type == PayloadType::Size)
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        let result = session
+            .handle_data_channel_event(DataChannelEvent::HandshakeComplete {
+                session_type: Some(SessionTypeRequest {
+                    session_type: config::SHELL_PLUGIN_NAME.to_string(),
+                    ..Default::default()
+                }),
+                customer_message: String::new(),
+            })
+            .await
+            .expect("Handshake complete event should be handled.");
+
+        assert_eq!(result, ControlFlow::Continue(()));
+    }
+
+    #[tokio::test]
+    async fn stderr_is_kept_separate_from_stdout() {
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+
+        let mut session = SessionBuilder::new()
+            .with_data_channel(MockDataChannel::new())
+            .with_output_writers(stdout.clone(), stderr.clone())
+            .build();
+
+        for (payload_type, payload) in [
+            (PayloadType::Output, b"out ".as_slice()),
+            (PayloadType::StdErr, b"err"),
+            (PayloadType::Output, b"put"),
+        ] {
+            let result = session
+                .handle_data_channel_event(DataChannelEvent::StreamData {
+                    payload_type,
+                    payload: payload.to_vec(),
+                })
+                .await
+                .expect("Stream data should be written.");
+            assert_eq!(result, ControlFlow::Continue(()));
+        }
+
+        assert_eq!(*stdout.0.lock().unwrap(), b"out put");
+        assert_eq!(*stderr.0.lock().unwrap(), b"err");
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn exit_code_is_reported_when_session_ends() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_close()
+            .once()
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        let mut session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .build();
+
+        let result = session
+            .handle_data_channel_event(DataChannelEvent::ExitCode(3))
+            .await
+            .expect("Exit code event should be handled.");
+        assert_eq!(result, ControlFlow::Continue(()));
+
+        let result = session
+            .handle_data_channel_event(DataChannelEvent::ChannelClosed(ChannelClosed::default()))
+            .await
+            .expect("Channel closed event should be handled.");
+        assert_eq!(
+            result,
+            ControlFlow::Break(SessionOutcome { exit_code: Some(3) })
+        );
+    }
+
+    type LoopbackSession = Session<DefaultDataChannel<LoopbackWebsocketChannel>>;
+
+    /// A session over a loopback websocket, with the agent end and what the client sends it. Output is
+    /// written to the returned stdout and stderr buffers.
+    async fn loopback_session() -> (
+        LoopbackSession,
+        LoopbackWebsocketChannel,
+        IncomingMessages,
+        SharedBuffer,
+        SharedBuffer,
+    ) {
+        let (client, agent) =
+            LoopbackWebsocketChannel::pair("wss://loopback".to_string(), "token".to_string());
+        let agent_incoming = agent.subscribe();
+        agent.open().await.expect("Agent end should open.");
+        let data_channel = DefaultDataChannel::new(
+            "client".to_string(),
+            SESSION_ID.to_string(),
+            "instance".to_string(),
+            client,
+        );
+
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+        let session = SessionBuilder::new()
+            .with_session_id(SESSION_ID.to_string())
+            .with_data_channel(data_channel)
+            .with_output_writers(stdout.clone(), stderr.clone())
+            .build();
+
+        (session, agent, agent_incoming, stdout, stderr)
+    }
+
+    /// Send `message` from the agent end.
+    async fn agent_sends(agent: &LoopbackWebsocketChannel, message: &ClientMessage) {
+        agent
+            .send_message(
+                &message.serialize().expect("Message should serialize."),
+                BINARY_MESSAGE,
+            )
+            .await
+            .expect("Agent should send.");
+    }
+
+    #[tokio::test]
+    async fn execute_over_loopback_reports_exit_code() {
+        let (mut session, agent, _agent_incoming, stdout, _) = loopback_session().await;
+        agent_sends(
+            &agent,
+            &ClientMessage::builder()
+                .with_sequence_number(0)
+                .output_stream_data(PayloadType::Output, b"done".to_vec())
+                .expect("Payload should be valid.")
+                .build(),
+        )
+        .await;
+        agent_sends(
+            &agent,
+            &ClientMessage::builder()
+                .with_sequence_number(1)
+                .exit_code(42)
+                .expect("Exit code should fit.")
+                .build(),
+        )
+        .await;
+        agent.close().await.expect("Agent end should close.");
+
+        let outcome = session.execute().await.expect("Session should end.");
+
+        assert_eq!(
+            outcome,
+            SessionOutcome {
+                exit_code: Some(42)
+            }
+        );
+        assert_eq!(*stdout.0.lock().unwrap(), b"done");
+    }
+
+    /// Wait for the client to send a terminal size with `cols` columns, skipping everything else.
+    async fn receive_size(agent_incoming: &mut IncomingMessages, cols: u32) {
+        while let Some(received) = agent_incoming.recv().await {
+            let Ok(message) = ClientMessage::deserialize(&received.expect("Client should send."))
+            else {
+                continue;
+            };
+            if message.payload_type() == PayloadType::Size
+                && serde_json::from_slice::<SizeData>(message.payload()).ok()
+                    == Some(SizeData { cols, rows: 24 })
+            {
+                return;
+            }
+        }
+
+        panic!("Client should send a size with {cols} columns.");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn execute_over_loopback_sends_terminal_size_changes() {
+        let (mut session, agent, mut agent_incoming, _, _) = loopback_session().await;
+        let terminal = TestTerminal::new(80);
+        session.terminal_size = TerminalSizeWatcher::new()
+            .without_signals()
+            .with_test_terminal(terminal.clone());
+        agent_sends(
+            &agent,
+            &ClientMessage::builder()
+                .with_sequence_number(0)
+                .output_stream_data(
+                    PayloadType::HandshakeRequestPayloadType,
+                    br#"{
+                        "AgentVersion": "3.3.40.0",
+                        "RequestedClientActions": [{
+                            "ActionType": "SessionType",
+                            "ActionParameters": { "SessionType": "Standard_Stream", "Properties": null }
+                        }]
+                    }"#
+                    .to_vec(),
+                )
+                .expect("Payload should be valid.")
+                .build(),
+        )
+        .await;
+        agent_sends(
+            &agent,
+            &ClientMessage::builder()
+                .with_sequence_number(1)
+                .output_stream_data(
+                    PayloadType::HandshakeCompletePayloadType,
+                    br#"{"HandshakeTimeToComplete": 1000000, "CustomerMessage": ""}"#.to_vec(),
+                )
+                .expect("Payload should be valid.")
+                .build(),
+        )
+        .await;
+
+        let (outcome, ()) = tokio::join!(session.execute(), async {
+            receive_size(&mut agent_incoming, 80).await;
+            terminal.resize(120);
+            tokio::time::timeout(
+                RESIZE_POLL_INTERVAL * 3,
+                receive_size(&mut agent_incoming, 120),
+            )
+            .await
+            .expect("Size change should be sent.");
+
+            agent_sends(
+                &agent,
+                &ClientMessage::builder()
+                    .channel_closed(&ChannelClosed::default())
+                    .expect("Payload should be valid.")
+                    .build(),
+            )
+            .await;
+        });
+
+        assert_eq!(
+            outcome.expect("Session should end."),
+            SessionOutcome::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_over_loopback_ends_when_agent_closes_channel() {
+        let (mut session, agent, mut agent_incoming, _, _) = loopback_session().await;
+        agent_sends(
+            &agent,
+            &ClientMessage::builder()
+                .channel_closed(&ChannelClosed {
+                    output: "Session terminated".to_string(),
+                    ..Default::default()
+                })
+                .expect("Payload should be valid.")
+                .build(),
+        )
+        .await;
+
+        let outcome = session.execute().await.expect("Session should end.");
+
+        assert_eq!(outcome, SessionOutcome::default());
+        // The client closes its end of the connection, after sending the token.
+        let mut closed = false;
+        while let Ok(received) = agent_incoming.try_recv() {
+            closed = matches!(received, Err(crate::Error::WebsocketClosed));
+        }
+        assert!(closed, "Client should close the connection.");
+    }
+
+    #[tokio::test]
+    async fn execute_over_loopback_writes_stderr_separately() {
+        let (mut session, agent, _agent_incoming, stdout, stderr) = loopback_session().await;
+        for (sequence_number, payload_type, payload) in [
+            (0, PayloadType::Output, b"out ".as_slice()),
+            (1, PayloadType::StdErr, b"err"),
+            (2, PayloadType::Output, b"put"),
+        ] {
+            agent_sends(
+                &agent,
+                &ClientMessage::builder()
+                    .with_sequence_number(sequence_number)
+                    .output_stream_data(payload_type, payload.to_vec())
+                    .expect("Payload should be valid.")
+                    .build(),
+            )
+            .await;
+        }
+        agent_sends(
+            &agent,
+            &ClientMessage::builder()
+                .channel_closed(&ChannelClosed::default())
+                .expect("Payload should be valid.")
+                .build(),
+        )
+        .await;
+
+        session.execute().await.expect("Session should end.");
+
+        assert_eq!(*stdout.0.lock().unwrap(), b"out put");
+        assert_eq!(*stderr.0.lock().unwrap(), b"err");
+    }
+
+    #[tokio::test]
+    async fn execute_over_loopback_fails_if_connection_ends_early() {
+        let (mut session, agent, _agent_incoming, _, _) = loopback_session().await;
+        agent.close().await.expect("Agent end should close.");
+
+        assert!(matches!(
+            session.execute().await,
+            Err(crate::Error::WebsocketClosed)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn execute_resends_unacknowledged_messages() {
+        let (mut session, agent, mut agent_incoming, _, _) = loopback_session().await;
+        agent_sends(
+            &agent,
+            &ClientMessage::builder()
+                .with_sequence_number(0)
+                .output_stream_data(
+                    PayloadType::HandshakeRequestPayloadType,
+                    br#"{"AgentVersion": "3.3.40.0", "RequestedClientActions": []}"#.to_vec(),
+                )
+                .expect("Payload should be valid.")
+                .build(),
+        )
+        .await;
+
+        let (outcome, ()) = tokio::join!(session.execute(), async {
+            // The agent never acknowledges the handshake response, so it is sent again.
+            let resent = async {
+                let mut responses = 0;
+                while responses < 2 {
+                    let received = agent_incoming.recv().await.expect("Client should send.");
+                    if ClientMessage::deserialize(&received.expect("Client should send."))
+                        .is_ok_and(|message| {
+                            message.payload_type() == PayloadType::HandshakeResponsePayloadType
+                        })
+                    {
+                        responses += 1;
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(5), resent)
+                .await
+                .expect("Handshake response should be resent.");
+
+            agent_sends(
+                &agent,
+                &ClientMessage::builder()
+                    .channel_closed(&ChannelClosed::default())
+                    .expect("Payload should be valid.")
+                    .build(),
+            )
+            .await;
+        });
+
+        assert_eq!(
+            outcome.expect("Session should end."),
+            SessionOutcome::default()
+        );
+    }
+}
@@ -1,9 +1,46 @@
-// TODO: finish implementation
-#[derive(Debug)]
-pub struct DisplayMode {}
+use crate::message::PayloadType;
+use std::{
+    fmt::Debug,
+    io::{self, Write},
+};
+
+/// Writes stream data received from the agent to the local terminal. Ported from `DisplayMode` in the
+/// original implementation, which only ever wrote to stdout. Here `StdErr` payloads are written to
+/// stderr so that the remote command's error output stays out of its standard output.
+pub struct DisplayMode {
+    stdout: Box<dyn Write + Send>,
+    stderr: Box<dyn Write + Send>,
+}
+
+impl Debug for DisplayMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DisplayMode").finish_non_exhaustive()
+    }
+}
 
 impl DisplayMode {
     pub fn new() -> Self {
-        DisplayMode {}
+        Self::with_writers(io::stdout(), io::stderr())
+    }
+
+    pub fn with_writers(
+        stdout: impl Write + Send + 'static,
+        stderr: impl Write + Send + 'static,
+    ) -> Self {
+        DisplayMode {
+            stdout: Box::new(stdout),
+            stderr: Box::new(stderr),
+        }
+    }
+
+    /// Write a payload to stderr if it is `StdErr` data, and to stdout otherwise.
+    pub fn display_message(&mut self, payload_type: PayloadType, payload: &[u8]) -> io::Result<()> {
+        let out = match payload_type {
+            PayloadType::StdErr => &mut self.stderr,
+            _ => &mut self.stdout,
+        };
+
+        out.write_all(payload)?;
+        out.flush()
     }
 }
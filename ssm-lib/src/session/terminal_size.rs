@@ -0,0 +1,252 @@
+//! Keeps the agent informed of the size of the local terminal, so that full-screen programs on the
+//! target render correctly. Ported from `handleTerminalResize` in the original implementation, which
+//! polls the terminal size every 500ms. Here the size is resent whenever the terminal reports a resize
+//! with `SIGWINCH`, and polling is only used where that signal is unavailable.
+
+use crate::{Error, data_channel::DataChannel, message::PayloadType, message::SizeData};
+use std::{convert::Infallible, fmt::Debug, future::pending, time::Duration};
+
+/// How often the terminal size is checked when resize signals are unavailable.
+pub const RESIZE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The size reported when the terminal size cannot be determined, for example when output is redirected.
+/// Matches the original implementation.
+pub const FALLBACK_SIZE: SizeData = SizeData {
+    cols: 300,
+    rows: 100,
+};
+
+/// Sends the terminal size to the agent whenever it changes.
+pub struct TerminalSizeWatcher {
+    last_sent: Option<SizeData>,
+    use_signals: bool,
+    current_size: Box<dyn Fn() -> SizeData + Send + Sync>,
+}
+
+impl Debug for TerminalSizeWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TerminalSizeWatcher")
+            .field("last_sent", &self.last_sent)
+            .field("use_signals", &self.use_signals)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for TerminalSizeWatcher {
+    fn default() -> Self {
+        Self {
+            last_sent: None,
+            use_signals: true,
+            current_size: Box::new(current_size),
+        }
+    }
+}
+
+impl TerminalSizeWatcher {
+    /// Create a watcher that has not yet sent a size.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Poll the terminal size instead of listening for resize signals.
+    #[must_use]
+    pub fn without_signals(mut self) -> Self {
+        self.use_signals = false;
+        self
+    }
+
+    /// Read the terminal size from `terminal` instead of from the real terminal.
+    #[cfg(test)]
+    pub(crate) fn with_test_terminal(mut self, terminal: TestTerminal) -> Self {
+        self.current_size = Box::new(move || terminal.size());
+        self
+    }
+
+    /// Send the current terminal size to the agent if it differs from the size last sent.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if the size message cannot be sent.
+    pub async fn send_if_changed(&mut self, data_channel: &impl DataChannel) -> Result<(), Error> {
+        let size = (self.current_size)();
+
+        if self.last_sent == Some(size) {
+            return Ok(());
+        }
+
+        log::debug!("Sending terminal size: {size:?}");
+
+        let payload =
+            serde_json::to_vec(&size).map_err(|e| Error::InvalidClientMessage(e.into()))?;
+        data_channel
+            .send_input_data_message(PayloadType::Size, &payload)
+            .await?;
+        self.last_sent = Some(size);
+
+        Ok(())
+    }
+
+    /// Send the current terminal size, then resend it every time it changes. Only returns if a size
+    /// message cannot be sent.
+    ///
+    /// ## Errors
+    ///
+    /// Returns an error if a size message cannot be sent.
+    pub async fn watch(&mut self, data_channel: &impl DataChannel) -> Result<Infallible, Error> {
+        let mut resize_signal = self.use_signals.then(ResizeSignal::new).flatten();
+        let mut poll = tokio::time::interval(RESIZE_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        self.send_if_changed(data_channel).await?;
+
+        loop {
+            tokio::select! {
+                received = next_resize(&mut resize_signal), if resize_signal.is_some() => {
+                    if !received {
+                        log::warn!("Terminal resize signals stopped; polling the terminal size instead");
+                        resize_signal = None;
+                    }
+                }
+                _ = poll.tick(), if resize_signal.is_none() => {}
+            }
+
+            self.send_if_changed(data_channel).await?;
+        }
+    }
+}
+
+/// Wait for the next resize signal. Returns `false` if no more signals will be received.
+async fn next_resize(resize_signal: &mut Option<ResizeSignal>) -> bool {
+    match resize_signal {
+        Some(resize_signal) => resize_signal.recv().await,
+        None => pending().await,
+    }
+}
+
+#[cfg(unix)]
+#[derive(Debug)]
+struct ResizeSignal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl ResizeSignal {
+    fn new() -> Option<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        signal(SignalKind::window_change())
+            .inspect_err(|e| log::warn!("Cannot listen for terminal resize signals: {e}"))
+            .ok()
+            .map(Self)
+    }
+
+    async fn recv(&mut self) -> bool {
+        self.0.recv().await.is_some()
+    }
+}
+
+/// Resize signals only exist on unix, so other platforms always poll.
+#[cfg(not(unix))]
+#[derive(Debug)]
+struct ResizeSignal;
+
+#[cfg(not(unix))]
+impl ResizeSignal {
+    fn new() -> Option<Self> {
+        None
+    }
+
+    async fn recv(&mut self) -> bool {
+        false
+    }
+}
+
+/// A terminal with 24 rows whose number of columns tests can change while a watcher reads it.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct TestTerminal(std::sync::Arc<std::sync::atomic::AtomicU32>);
+
+#[cfg(test)]
+impl TestTerminal {
+    pub(crate) fn new(cols: u32) -> Self {
+        Self(std::sync::Arc::new(cols.into()))
+    }
+
+    pub(crate) fn resize(&self, cols: u32) {
+        self.0.store(cols, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub(crate) fn size(&self) -> SizeData {
+        SizeData {
+            cols: self.0.load(std::sync::atomic::Ordering::SeqCst),
+            rows: 24,
+        }
+    }
+}
+
+fn current_size() -> SizeData {
+    if let Some((terminal_size::Width(cols), terminal_size::Height(rows))) =
+        terminal_size::terminal_size()
+    {
+        SizeData {
+            cols: cols.into(),
+            rows: rows.into(),
+        }
+    } else {
+        log::debug!("Could not get size of the terminal, using {FALLBACK_SIZE:?}");
+        FALLBACK_SIZE
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RESIZE_POLL_INTERVAL, TerminalSizeWatcher, TestTerminal};
+    use crate::{
+        data_channel::MockDataChannel,
+        message::{PayloadType, SizeData},
+    };
+
+    fn sends_size(cols: u32) -> impl Fn(&PayloadType, &[u8]) -> bool {
+        move |payload_type, payload| {
+            *payload_type == PayloadType::Size
+                && serde_json::from_slice::<SizeData>(payload).ok()
+                    == Some(SizeData { cols, rows: 24 })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resend_size_only_when_changed() {
+        let mut data_channel = MockDataChannel::new();
+        data_channel
+            .expect_send_input_data_message()
+            .once()
+            .withf(sends_size(80))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+        data_channel
+            .expect_send_input_data_message()
+            .once()
+            .withf(sends_size(120))
+            .returning(|_, _| Box::pin(async { Ok(()) }));
+
+        let terminal = TestTerminal::new(80);
+        let mut watcher = TerminalSizeWatcher::new()
+            .without_signals()
+            .with_test_terminal(terminal.clone());
+
+        tokio::select! {
+            _ = watcher.watch(&data_channel) => panic!("Watcher should not stop."),
+            () = async {
+                tokio::time::sleep(RESIZE_POLL_INTERVAL * 3).await;
+                terminal.resize(120);
+                tokio::time::sleep(RESIZE_POLL_INTERVAL * 3).await;
+            } => {}
+        }
+
+        assert_eq!(
+            watcher.last_sent,
+            Some(SizeData {
+                cols: 120,
+                rows: 24
+            })
+        );
+    }
+}
@@ -0,0 +1,16 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Acquire the lock on a mutex. The state guarded by the library's mutexes cannot be left inconsistent by a
+/// panic while the lock was held, so we recover the guard from a poisoned mutex rather than propagating the
+/// panic.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(e) => {
+            log::error!(
+                "Thread panicked while holding a Mutex lock. Please report to the crate's maintainers: {e}"
+            );
+            e.into_inner()
+        }
+    }
+}
@@ -1,8 +1,62 @@
-//! TODO: add module documentation
+//! The websocket connection to the data channel endpoint of the Message Gateway Service (MGS).
+//!
+//! Roughly corresponds to [websocketchannel.go](https://github.com/aws/session-manager-plugin/blob/mainline/src/communicator/websocketchannel.go)
+//! in the original implementation.
+
+use crate::{config, sync::lock};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_tungstenite::{
+    Connector, MaybeTlsStream, WebSocketStream,
+    tungstenite::{
+        Message,
+        client::IntoClientRequest,
+        error::{Error as TungsteniteError, UrlError},
+        http::Uri,
+    },
+};
+
+mod loopback;
+mod proxy;
+mod tls;
+
+pub use loopback::LoopbackWebsocketChannel;
+pub use proxy::Proxy;
+pub use tls::{Error as TlsError, TlsConfig};
+
+/// Send the input of [`WebsocketChannel::send_message`] as a text frame. Matches `websocket.TextMessage` in
+/// the original implementation.
+pub const TEXT_MESSAGE: u32 = 1;
+
+/// Send the input of [`WebsocketChannel::send_message`] as a binary frame. Matches `websocket.BinaryMessage`
+/// in the original implementation.
+pub const BINARY_MESSAGE: u32 = 2;
+
+/// A frame received from the websocket, or the error which ended the connection. Frames are handed over
+/// as received, without being copied.
+pub type IncomingMessage = Result<Bytes, crate::Error>;
+
+/// The receiving end of a [`WebsocketChannel::subscribe`] registration.
+///
+/// The queue is unbounded, so that a subscriber which is busy, for example closing the channel, can never
+/// stall the connection. Frames the subscriber does not keep up with are buffered in memory, so receive
+/// them promptly.
+pub type IncomingMessages = UnboundedReceiver<IncomingMessage>;
 
 /// TODO: Add a description of the data channel.
 #[mockall::automock]
-pub trait WebsocketChannel {
+pub trait WebsocketChannel: Send + Sync {
     /// TODO: document
     fn get_stream_url(&self) -> &str;
 
@@ -13,26 +67,93 @@ pub trait WebsocketChannel {
     ///
     /// ## Errors
     /// TODO: document errors
-    fn close(&self) -> Result<(), crate::Error>;
+    fn close(&self) -> impl Future<Output = Result<(), crate::Error>> + Send;
 
     /// TODO: document
     ///
     /// ## Errors
     /// TODO: document errors
-    fn open(&self) -> Result<(), crate::Error>;
+    fn open(&self) -> impl Future<Output = Result<(), crate::Error>> + Send;
 
-    /// TODO: document
+    /// Send `input` to the remote end as a single frame. `input_type` is either [`TEXT_MESSAGE`] or
+    /// [`BINARY_MESSAGE`].
     ///
     /// ## Errors
     /// TODO: document errors
-    fn send_message(&self, input: &[u8], input_type: u32) -> Result<(), crate::Error>;
+    fn send_message(
+        &self,
+        input: &[u8],
+        input_type: u32,
+    ) -> impl Future<Output = Result<(), crate::Error>> + Send;
+
+    /// Register to receive the frames sent by the remote end, along with the errors which end a
+    /// connection, in the order they occur. Replaces `SetOnMessage` and `SetOnError` in the original
+    /// implementation.
+    ///
+    /// Only the latest registration receives messages; earlier receivers are closed. A registration
+    /// carries over when the channel reconnects, so subscribe once, before calling
+    /// [`WebsocketChannel::open`], to not miss any messages.
+    fn subscribe(&self) -> IncomingMessages;
 }
 
-/// Default [`WebsocketChannel`] implementation.
-#[derive(Debug, Default)]
+/// Default [`WebsocketChannel`] implementation, which connects to the stream url over TLS.
+///
+/// Once open, the connection runs on a background task of the current tokio runtime. Frames passed to
+/// [`WebsocketChannel::send_message`] are queued and written by that task. Frames it receives are
+/// delivered to the receiver returned by [`WebsocketChannel::subscribe`], and dropped if there is none.
 pub struct DefaultWebsocketChannel {
     stream_url: String,
     channel_token: String,
+    connector: Option<Connector>,
+    proxy: ProxySetting,
+    keepalive: Keepalive,
+    connection: Mutex<Option<Connection>>,
+    subscriber: Subscriber,
+}
+
+/// The sending end of the current [`WebsocketChannel::subscribe`] registration, shared with the
+/// connection task.
+type Subscriber = Arc<Mutex<Option<UnboundedSender<IncomingMessage>>>>;
+
+/// Where the proxy for the connection comes from.
+#[derive(Debug, Clone)]
+enum ProxySetting {
+    /// Read the proxy from the environment each time the channel opens.
+    FromEnv,
+    /// Use the given proxy, or connect directly.
+    Fixed(Option<Proxy>),
+}
+
+/// How often to ping the remote end, and how long to wait for the pong.
+#[derive(Debug, Clone, Copy)]
+struct Keepalive {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+}
+
+/// The handle to an open connection.
+#[derive(Debug)]
+struct Connection {
+    outgoing: UnboundedSender<Message>,
+    task: JoinHandle<()>,
+}
+
+impl Debug for DefaultWebsocketChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DefaultWebsocketChannel")
+            .field("stream_url", &self.stream_url)
+            .field("channel_token", &"<redacted>")
+            .field("proxy", &self.proxy)
+            .field("keepalive", &self.keepalive)
+            .field("connection", &self.connection)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for DefaultWebsocketChannel {
+    fn default() -> Self {
+        Self::new(String::new(), String::new())
+    }
 }
 
 impl WebsocketChannel for DefaultWebsocketChannel {
@@ -44,16 +165,118 @@ impl WebsocketChannel for DefaultWebsocketChannel {
         &self.channel_token
     }
 
-    fn close(&self) -> Result<(), crate::Error> {
-        todo!()
+    /// Close the connection. Frames already queued are sent before the close frame, and the connection
+    /// task is awaited, so nothing from this connection is delivered to the subscriber once this returns.
+    /// If the remote end does not answer the close frame within the pong timeout, the connection is
+    /// dropped. Closing a channel which is not open does nothing.
+    async fn close(&self) -> Result<(), crate::Error> {
+        let connection = self.connection().take();
+
+        if let Some(Connection { outgoing, task }) = connection {
+            log::info!(
+                "Closing websocket channel connection to: {}",
+                self.stream_url
+            );
+            // Dropping the sender tells the connection task to send a close frame once the queue is empty.
+            drop(outgoing);
+            finish(task).await;
+        }
+
+        Ok(())
+    }
+
+    /// Connect to the stream url, through the proxy if one is configured, then relay frames on a background
+    /// task. Errors which end the connection after it is established are delivered to the subscriber.
+    ///
+    /// ## Errors
+    ///
+    /// * [`crate::Error::NoRuntime`] if called outside of a tokio runtime.
+    /// * [`crate::Error::InvalidProxyUrl`] if the proxy in the environment is invalid.
+    /// * [`crate::Error::ProxyConnect`] or [`crate::Error::ProxyRejected`] if the proxy cannot open a
+    ///   tunnel.
+    /// * [`crate::Error::Websocket`] if the connection cannot be established.
+    async fn open(&self) -> Result<(), crate::Error> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(crate::Error::NoRuntime)?;
+
+        log::info!("Opening websocket connection to: {}", self.stream_url);
+
+        let request = self
+            .stream_url
+            .as_str()
+            .into_client_request()
+            .map_err(websocket_error)?;
+        let (host, port) = host_and_port(request.uri())?;
+        let proxy = match &self.proxy {
+            ProxySetting::FromEnv => Proxy::from_env()?,
+            ProxySetting::Fixed(proxy) => proxy.clone(),
+        };
+
+        let socket = match proxy.filter(|proxy| proxy.is_used_for(&host, port)) {
+            Some(proxy) => {
+                log::debug!("Connecting to {host}:{port} through proxy {proxy:?}");
+                proxy.connect(&host, port).await?
+            }
+            None => tokio::net::TcpStream::connect((proxy::unbracket(&host), port))
+                .await
+                .map_err(|e| websocket_error(TungsteniteError::Io(e)))?,
+        };
+
+        let (stream, _) = tokio_tungstenite::client_async_tls_with_config(
+            request,
+            socket,
+            None,
+            self.connector.clone(),
+        )
+        .await
+        .map_err(websocket_error)?;
+
+        log::debug!("Websocket connection to {} established", self.stream_url);
+
+        let (outgoing, outgoing_receiver) = mpsc::unbounded_channel();
+        let task = runtime.spawn(run_connection(
+            self.stream_url.clone(),
+            stream,
+            self.keepalive,
+            outgoing_receiver,
+            Arc::clone(&self.subscriber),
+        ));
+
+        let previous = self.connection().replace(Connection { outgoing, task });
+        if let Some(previous) = previous {
+            previous.task.abort();
+            finish(previous.task).await;
+        }
+
+        Ok(())
     }
 
-    fn open(&self) -> Result<(), crate::Error> {
-        todo!()
+    /// ## Errors
+    ///
+    /// * [`crate::Error::InvalidWebsocketMessageType`] if `input_type` is not a known message type.
+    /// * [`crate::Error::InvalidTextMessage`] if a text message is not valid UTF-8.
+    /// * [`crate::Error::WebsocketNotOpen`] if the channel is not open.
+    async fn send_message(&self, input: &[u8], input_type: u32) -> Result<(), crate::Error> {
+        let message = match input_type {
+            TEXT_MESSAGE => Message::text(
+                String::from_utf8(input.to_vec()).map_err(crate::Error::InvalidTextMessage)?,
+            ),
+            BINARY_MESSAGE => Message::binary(input.to_vec()),
+            input_type => Err(crate::Error::InvalidWebsocketMessageType(input_type))?,
+        };
+
+        self.connection()
+            .as_ref()
+            .ok_or(crate::Error::WebsocketNotOpen)?
+            .outgoing
+            .send(message)
+            .map_err(|_| crate::Error::WebsocketNotOpen)
     }
 
-    fn send_message(&self, _input: &[u8], _input_type: u32) -> Result<(), crate::Error> {
-        todo!()
+    fn subscribe(&self) -> IncomingMessages {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *lock(&self.subscriber) = Some(sender);
+
+        receiver
     }
 }
 
@@ -64,6 +287,640 @@ impl DefaultWebsocketChannel {
         Self {
             stream_url,
             channel_token,
+            connector: None,
+            proxy: ProxySetting::FromEnv,
+            keepalive: Keepalive {
+                ping_interval: Duration::from_millis(config::PING_TIME_INTERVAL_MILLIS),
+                pong_timeout: Duration::from_millis(config::PONG_TIMEOUT_MILLIS),
+            },
+            connection: Mutex::default(),
+            subscriber: Subscriber::default(),
         }
     }
+
+    /// Ping the remote end every `ping_interval` while the connection is open. If a ping is not answered
+    /// within `pong_timeout`, the connection is dropped and [`crate::Error::WebsocketPongTimeout`] is
+    /// delivered to the subscriber. The remote end has as long to answer the close frame when the channel
+    /// is closed. Defaults to [`config::PING_TIME_INTERVAL_MILLIS`] and [`config::PONG_TIMEOUT_MILLIS`].
+    #[must_use]
+    pub fn with_keepalive(mut self, ping_interval: Duration, pong_timeout: Duration) -> Self {
+        self.keepalive = Keepalive {
+            ping_interval,
+            pong_timeout,
+        };
+        self
+    }
+
+    /// Connect through `proxy`, or directly if it is `None`, instead of through the proxy set by the
+    /// `HTTPS_PROXY` and `NO_PROXY` environment variables. See [`Proxy::from_env`].
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = ProxySetting::Fixed(proxy);
+        self
+    }
+
+    /// Verify the server and authenticate the client with `tls_config` instead of trusting the bundled
+    /// public CAs.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`crate::Error::TlsConfig`] if the configuration cannot be built, see [`TlsConfig::build`].
+    pub fn with_tls_config(mut self, tls_config: &TlsConfig) -> Result<Self, crate::Error> {
+        let client_config = tls_config.build().map_err(crate::Error::TlsConfig)?;
+        self.connector = Some(Connector::Rustls(Arc::new(client_config)));
+        Ok(self)
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Option<Connection>> {
+        lock(&self.connection)
+    }
+}
+
+type Stream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Wait for a connection task to end, so that it cannot deliver to the subscriber of a newer connection.
+async fn finish(task: JoinHandle<()>) {
+    if let Err(e) = task.await
+        && e.is_panic()
+    {
+        log::error!("Websocket connection task panicked: {e}");
+    }
+}
+
+/// Write queued frames and forward received frames until the connection ends. The error which ends the
+/// connection, if any, is forwarded as well.
+async fn run_connection(
+    stream_url: String,
+    stream: Stream,
+    keepalive: Keepalive,
+    outgoing: UnboundedReceiver<Message>,
+    subscriber: Subscriber,
+) {
+    if let Err(e) = relay(&stream_url, stream, keepalive, outgoing, &subscriber).await {
+        log::error!("Websocket connection to {stream_url} failed: {e}");
+        deliver(&subscriber, Err(e));
+    }
+}
+
+/// Hand a message to the current subscriber. Without one, or once it has gone away, nobody is
+/// interested in the message.
+fn deliver(subscriber: &Subscriber, message: IncomingMessage) {
+    let delivered = lock(subscriber)
+        .as_ref()
+        .is_some_and(|sender| sender.send(message).is_ok());
+
+    if !delivered {
+        log::debug!("Dropping incoming websocket message without a subscriber");
+    }
+}
+
+async fn relay(
+    stream_url: &str,
+    stream: Stream,
+    keepalive: Keepalive,
+    mut outgoing: UnboundedReceiver<Message>,
+    subscriber: &Subscriber,
+) -> Result<(), crate::Error> {
+    let (mut sink, mut stream) = stream.split();
+    let mut closing = false;
+    let mut close_received = false;
+
+    let mut ping = tokio::time::interval_at(
+        Instant::now() + keepalive.ping_interval,
+        keepalive.ping_interval,
+    );
+    ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let pong_deadline = tokio::time::sleep(keepalive.pong_timeout);
+    tokio::pin!(pong_deadline);
+    let mut awaiting_pong = false;
+
+    loop {
+        tokio::select! {
+            _ = ping.tick(), if !closing => {
+                log::trace!("Sending ping to {stream_url}");
+                sink.send(Message::Ping(Vec::new().into())).await.map_err(websocket_error)?;
+                // Measure from the oldest unanswered ping, so a connection which never answers is noticed.
+                if !awaiting_pong {
+                    awaiting_pong = true;
+                    pong_deadline.as_mut().reset(Instant::now() + keepalive.pong_timeout);
+                }
+            }
+            () = &mut pong_deadline, if awaiting_pong || closing => {
+                if closing {
+                    log::warn!("Websocket connection to {stream_url} did not answer the close frame in time");
+                    return Ok(());
+                }
+                log::warn!("Websocket connection to {stream_url} did not answer a ping in time");
+                return Err(crate::Error::WebsocketPongTimeout(keepalive.pong_timeout));
+            }
+            message = outgoing.recv(), if !closing => {
+                if let Some(message) = message {
+                    sink.send(message).await.map_err(websocket_error)?;
+                } else {
+                    // Keep reading until the remote end acknowledges the close frame, or the deadline passes.
+                    closing = true;
+                    pong_deadline.as_mut().reset(Instant::now() + keepalive.pong_timeout);
+                    if let Err(e) = sink.close().await {
+                        log::debug!("Failed to send close frame to {stream_url}: {e}");
+                        return Ok(());
+                    }
+                }
+            }
+            message = stream.next() => match message {
+                Some(Ok(Message::Binary(data))) => deliver(subscriber, Ok(data)),
+                Some(Ok(Message::Text(text))) => deliver(subscriber, Ok(text.into())),
+                Some(Ok(Message::Close(frame))) => {
+                    log::debug!("Received close frame from {stream_url}: {frame:?}");
+                    close_received = true;
+                }
+                Some(Ok(Message::Pong(_))) => awaiting_pong = false,
+                // Pings are answered by tungstenite.
+                Some(Ok(Message::Ping(_) | Message::Frame(_))) => {}
+                Some(Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed)) | None => {
+                    return connection_ended(stream_url, closing);
+                }
+                // Many servers drop the connection without a TLS close_notify once the close handshake is done.
+                // Once closed locally, nobody is interested in how the connection ended.
+                Some(Err(e)) if close_received || closing => {
+                    log::debug!("Websocket connection to {stream_url} ended after close frame: {e}");
+                    return connection_ended(stream_url, closing);
+                }
+                Some(Err(e)) => return Err(websocket_error(e)),
+            },
+        }
+    }
+}
+
+/// The connection ended after the close handshake, which is only expected if it was closed locally.
+fn connection_ended(stream_url: &str, closing: bool) -> Result<(), crate::Error> {
+    if closing {
+        log::debug!("Websocket connection to {stream_url} closed");
+        Ok(())
+    } else {
+        Err(crate::Error::WebsocketClosed)
+    }
+}
+
+/// The host and port to connect to for a websocket url. IPv6 hosts keep their brackets.
+fn host_and_port(uri: &Uri) -> Result<(String, u16), crate::Error> {
+    let host = uri
+        .host()
+        .ok_or(TungsteniteError::Url(UrlError::NoHostName))
+        .map_err(websocket_error)?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("wss")) => 443,
+        (None, Some("ws")) => 80,
+        _ => Err(websocket_error(TungsteniteError::Url(
+            UrlError::UnsupportedUrlScheme,
+        )))?,
+    };
+
+    Ok((host.to_string(), port))
+}
+
+fn websocket_error(error: TungsteniteError) -> crate::Error {
+    crate::Error::Websocket(Box::new(error))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        BINARY_MESSAGE, DefaultWebsocketChannel, Proxy, TEXT_MESSAGE, TlsConfig, WebsocketChannel,
+    };
+    use bytes::Bytes;
+    use futures_util::{FutureExt, SinkExt, StreamExt};
+    use rustls::{
+        RootCertStore, ServerConfig,
+        pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+        server::{WebPkiClientVerifier, danger::ClientCertVerifier},
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc::error::TryRecvError,
+        task::JoinHandle,
+    };
+    use tokio_rustls::{TlsAcceptor, server::TlsStream};
+    use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
+
+    type ServerStream = WebSocketStream<TlsStream<tokio::net::TcpStream>>;
+
+    /// Start a `wss://` server for `localhost` with a self-signed certificate, which accepts a single
+    /// connection. Returns a channel which trusts the certificate and the server end of the connection.
+    async fn local_wss_server() -> (DefaultWebsocketChannel, JoinHandle<ServerStream>) {
+        let (url, tls_config, server) =
+            local_wss_server_with_client_verifier(WebPkiClientVerifier::no_client_auth()).await;
+
+        let channel = DefaultWebsocketChannel::new(url, "token".to_string())
+            .with_tls_config(&tls_config)
+            .expect("TLS config should be valid.")
+            .with_proxy(None);
+
+        (channel, server)
+    }
+
+    /// Like [`local_wss_server`], but authenticates clients with `client_verifier`. Returns the server url,
+    /// a [`TlsConfig`] which trusts only the server certificate, and the server end of the connection.
+    async fn local_wss_server_with_client_verifier(
+        client_verifier: Arc<dyn ClientCertVerifier>,
+    ) -> (String, TlsConfig, JoinHandle<ServerStream>) {
+        let certified_key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("Certificate should be generated.");
+        let certificate = CertificateDer::from(certified_key.cert.der().to_vec());
+        let key = PrivatePkcs8KeyDer::from(certified_key.key_pair.serialize_der());
+
+        let server_config = ServerConfig::builder()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(vec![certificate], key.into())
+            .expect("Server config should be valid.");
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Listener should bind.");
+        let port = listener
+            .local_addr()
+            .expect("Listener should have an address.")
+            .port();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("Client should connect.");
+            let stream = acceptor
+                .accept(stream)
+                .await
+                .expect("TLS handshake should succeed.");
+            tokio_tungstenite::accept_async(stream)
+                .await
+                .expect("Websocket handshake should succeed.")
+        });
+
+        let tls_config = TlsConfig::new()
+            .without_webpki_roots()
+            .with_root_certificates_pem(certified_key.cert.pem().as_bytes())
+            .expect("Certificate should be valid.");
+
+        (format!("wss://localhost:{port}"), tls_config, server)
+    }
+
+    #[tokio::test]
+    async fn exchange_messages_over_tls() {
+        let (channel, server) = local_wss_server().await;
+        let mut incoming = channel.subscribe();
+
+        channel.open().await.expect("Channel should open.");
+        channel
+            .send_message(b"token", TEXT_MESSAGE)
+            .await
+            .expect("Message should be queued.");
+
+        let mut server = server.await.expect("Server should accept a connection.");
+        assert_eq!(
+            server.next().await.and_then(Result::ok),
+            Some(Message::text("token"))
+        );
+
+        server
+            .send(Message::binary(b"from agent".to_vec()))
+            .await
+            .expect("Server should send.");
+        assert_eq!(
+            incoming.recv().await.and_then(Result::ok),
+            Some(Bytes::from_static(b"from agent"))
+        );
+
+        channel
+            .send_message(&[0, 1, 2], BINARY_MESSAGE)
+            .await
+            .expect("Message should be queued.");
+        assert_eq!(
+            server.next().await.and_then(Result::ok),
+            Some(Message::binary(vec![0, 1, 2]))
+        );
+
+        // The server has to keep reading to answer the close frame, for the close to complete.
+        let server = tokio::spawn(async move {
+            let close_frame = server.next().await;
+            while server.next().await.is_some() {}
+            close_frame
+        });
+        channel.close().await.expect("Channel should close.");
+        assert!(matches!(
+            server.await.expect("Server should finish."),
+            Some(Ok(Message::Close(_)))
+        ));
+        assert!(matches!(
+            channel.send_message(b"late", BINARY_MESSAGE).await,
+            Err(crate::Error::WebsocketNotOpen)
+        ));
+    }
+
+    #[tokio::test]
+    async fn present_client_certificate() {
+        let client = rcgen::generate_simple_self_signed(vec!["client".to_string()])
+            .expect("Certificate should be generated.");
+        let mut client_roots = RootCertStore::empty();
+        client_roots
+            .add(CertificateDer::from(client.cert.der().to_vec()))
+            .expect("Certificate should be valid.");
+        let client_verifier = WebPkiClientVerifier::builder(Arc::new(client_roots))
+            .build()
+            .expect("Client verifier should be valid.");
+
+        let (url, tls_config, _server) =
+            local_wss_server_with_client_verifier(client_verifier.clone()).await;
+        let channel = DefaultWebsocketChannel::new(url, "token".to_string())
+            .with_tls_config(&tls_config)
+            .expect("TLS config should be valid.")
+            .with_proxy(None);
+        assert!(matches!(
+            channel.open().await,
+            Err(crate::Error::Websocket(_))
+        ));
+
+        let (url, tls_config, server) =
+            local_wss_server_with_client_verifier(client_verifier).await;
+        let tls_config = tls_config
+            .with_client_certificate_pem(
+                client.cert.pem().as_bytes(),
+                client.key_pair.serialize_pem().as_bytes(),
+            )
+            .expect("Client certificate should be valid.");
+        let channel = DefaultWebsocketChannel::new(url, "token".to_string())
+            .with_tls_config(&tls_config)
+            .expect("TLS config should be valid.")
+            .with_proxy(None);
+
+        channel.open().await.expect("Channel should open.");
+        channel
+            .send_message(b"token", TEXT_MESSAGE)
+            .await
+            .expect("Message should be queued.");
+
+        let mut server = server.await.expect("Server should accept a connection.");
+        assert_eq!(
+            server.next().await.and_then(Result::ok),
+            Some(Message::text("token"))
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_is_delivered_after_close() {
+        let (channel, server) = local_wss_server().await;
+        let mut incoming = channel.subscribe();
+
+        channel.open().await.expect("Channel should open.");
+        let mut server = server.await.expect("Server should accept a connection.");
+        // Drop the connection without answering the close frame, which fails the connection task.
+        let server = tokio::spawn(async move {
+            let close_frame = server.next().await;
+            drop(server);
+            close_frame
+        });
+
+        channel.close().await.expect("Channel should close.");
+        assert!(matches!(
+            server.await.expect("Server should finish."),
+            Some(Ok(Message::Close(_)))
+        ));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), incoming.recv())
+                .await
+                .is_err(),
+            "Nothing should be delivered."
+        );
+    }
+
+    #[tokio::test]
+    async fn report_remote_close() {
+        let (channel, server) = local_wss_server().await;
+        let mut incoming = channel.subscribe();
+
+        channel.open().await.expect("Channel should open.");
+        let mut server = server.await.expect("Server should accept a connection.");
+        server.close(None).await.expect("Server should close.");
+        // Read the reply to the close frame, then drop the connection as a real server would.
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+
+        assert!(matches!(
+            incoming.recv().await,
+            Some(Err(crate::Error::WebsocketClosed))
+        ));
+    }
+
+    #[tokio::test]
+    async fn keepalive_pings_are_answered() {
+        let (channel, server) = local_wss_server().await;
+        let channel = channel.with_keepalive(Duration::from_millis(20), Duration::from_millis(200));
+        let mut incoming = channel.subscribe();
+
+        channel.open().await.expect("Channel should open.");
+        let mut server = server.await.expect("Server should accept a connection.");
+        // Reading answers the pings, as a live server would.
+        let pings = tokio::spawn(async move {
+            let mut pings = 0;
+            while let Some(Ok(message)) = server.next().await {
+                if message.is_ping() {
+                    pings += 1;
+                }
+            }
+            pings
+        });
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), incoming.recv())
+                .await
+                .is_err(),
+            "Connection should stay healthy."
+        );
+        channel.close().await.expect("Channel should close.");
+        assert!(pings.await.expect("Server should finish.") >= 2);
+    }
+
+    #[tokio::test]
+    async fn report_missing_pong() {
+        let (channel, server) = local_wss_server().await;
+        let channel = channel.with_keepalive(Duration::from_millis(20), Duration::from_millis(50));
+        let mut incoming = channel.subscribe();
+
+        channel.open().await.expect("Channel should open.");
+        // Never read from the server, so the pings are never answered.
+        let _server = server.await.expect("Server should accept a connection.");
+
+        assert!(matches!(
+            incoming.recv().await,
+            Some(Err(crate::Error::WebsocketPongTimeout(_)))
+        ));
+    }
+
+    /// Start a stand-in for an HTTP proxy, which answers a single `CONNECT` request with `status` and then
+    /// relays the tunnel if the status is 200. Returns the proxy url and the request header received.
+    async fn local_connect_proxy(status: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Listener should bind.");
+        let url = format!(
+            "http://user:pa%24%24@{}",
+            listener
+                .local_addr()
+                .expect("Listener should have an address.")
+        );
+
+        let proxy = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.expect("Client should connect.");
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(client.read_u8().await.expect("Request should be read."));
+            }
+            let request = String::from_utf8(request).expect("Request should be text.");
+
+            client
+                .write_all(format!("HTTP/1.1 {status}\r\nVia: stand-in\r\n\r\n").as_bytes())
+                .await
+                .expect("Response should be written.");
+
+            if status.starts_with("200") {
+                let target = request
+                    .split_whitespace()
+                    .nth(1)
+                    .expect("Request should name a target.")
+                    .replace("localhost", "127.0.0.1");
+                let mut server = tokio::net::TcpStream::connect(target)
+                    .await
+                    .expect("Proxy should reach the target.");
+                tokio::spawn(async move {
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+                });
+            }
+
+            request
+        });
+
+        (url, proxy)
+    }
+
+    #[tokio::test]
+    async fn connect_through_proxy() {
+        let (channel, server) = local_wss_server().await;
+        let (proxy_url, proxy) = local_connect_proxy("200 Connection established").await;
+        let port = channel
+            .get_stream_url()
+            .rsplit_once(':')
+            .map(|(_, port)| port.to_string())
+            .expect("Stream url should have a port.");
+        let channel = channel.with_proxy(Some(
+            Proxy::new(&proxy_url).expect("Proxy url should be valid."),
+        ));
+
+        channel.open().await.expect("Channel should open.");
+        channel
+            .send_message(b"token", TEXT_MESSAGE)
+            .await
+            .expect("Message should be queued.");
+
+        let request = proxy.await.expect("Proxy should receive a request.");
+        assert!(request.starts_with(&format!("CONNECT localhost:{port} HTTP/1.1\r\n")));
+        // user:pa$$
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYSQk\r\n"));
+
+        let mut server = server.await.expect("Server should accept a connection.");
+        assert_eq!(
+            server.next().await.and_then(Result::ok),
+            Some(Message::text("token"))
+        );
+    }
+
+    #[tokio::test]
+    async fn report_proxy_rejection() {
+        let (proxy_url, _proxy) = local_connect_proxy("407 Proxy Authentication Required").await;
+        let channel = DefaultWebsocketChannel::new(
+            "wss://ssmmessages.example:443/v1/data-channel/session".to_string(),
+            "token".to_string(),
+        )
+        .with_proxy(Some(
+            Proxy::new(&proxy_url)
+                .expect("Proxy url should be valid.")
+                .with_no_proxy("localhost"),
+        ));
+
+        assert!(matches!(
+            channel.open().await,
+            Err(crate::Error::ProxyRejected(407))
+        ));
+    }
+
+    #[tokio::test]
+    async fn close_without_answer() {
+        let (channel, server) = local_wss_server().await;
+        let channel = channel.with_keepalive(Duration::from_mins(1), Duration::from_millis(50));
+        let mut incoming = channel.subscribe();
+
+        channel.open().await.expect("Channel should open.");
+        // Never read from the server, so the close frame is never answered.
+        let _server = server.await.expect("Server should accept a connection.");
+
+        tokio::time::timeout(Duration::from_secs(5), channel.close())
+            .await
+            .expect("Close should not wait for an answer forever.")
+            .expect("Channel should close.");
+        assert!(matches!(incoming.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[tokio::test]
+    async fn report_connection_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Listener should bind.");
+        let port = listener
+            .local_addr()
+            .expect("Listener should have an address.")
+            .port();
+        drop(listener);
+
+        let channel =
+            DefaultWebsocketChannel::new(format!("wss://127.0.0.1:{port}"), "token".to_string())
+                .with_proxy(None);
+
+        assert!(matches!(
+            channel.open().await,
+            Err(crate::Error::Websocket(_))
+        ));
+    }
+
+    #[test]
+    fn reject_invalid_messages() {
+        let channel = DefaultWebsocketChannel::default();
+
+        assert!(matches!(
+            channel.send_message(b"message", 0).now_or_never(),
+            Some(Err(crate::Error::InvalidWebsocketMessageType(0)))
+        ));
+        assert!(matches!(
+            channel.send_message(&[0xff], TEXT_MESSAGE).now_or_never(),
+            Some(Err(crate::Error::InvalidTextMessage(_)))
+        ));
+        assert!(matches!(
+            channel
+                .send_message(b"message", BINARY_MESSAGE)
+                .now_or_never(),
+            Some(Err(crate::Error::WebsocketNotOpen))
+        ));
+        assert!(matches!(
+            channel.open().now_or_never(),
+            Some(Err(crate::Error::NoRuntime(_)))
+        ));
+    }
+
+    #[test]
+    fn only_latest_subscriber_receives_messages() {
+        let channel = DefaultWebsocketChannel::default();
+        let mut first = channel.subscribe();
+        let mut second = channel.subscribe();
+
+        super::deliver(&channel.subscriber, Ok(Bytes::from_static(b"frame")));
+
+        assert!(matches!(first.try_recv(), Err(TryRecvError::Disconnected)));
+        assert!(matches!(second.try_recv(), Ok(Ok(frame)) if frame == b"frame"[..]));
+    }
 }
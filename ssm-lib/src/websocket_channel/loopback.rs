@@ -0,0 +1,275 @@
+//! An in-memory [`WebsocketChannel`] pair, for testing code built on the data channel without a network
+//! connection or an agent.
+
+use super::{
+    BINARY_MESSAGE, IncomingMessages, Subscriber, TEXT_MESSAGE, WebsocketChannel, deliver,
+};
+use crate::sync::lock;
+use bytes::Bytes;
+use std::{
+    fmt::Debug,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use tokio::sync::mpsc;
+
+/// One end of an in-memory websocket connection. Create a connected pair with
+/// [`LoopbackWebsocketChannel::pair`]: pass the client end to
+/// [`crate::data_channel::DefaultDataChannel::new`], and play the agent on the other end by sending it
+/// serialized [`crate::message::ClientMessage`]s.
+///
+/// Frames sent from one end are delivered to the subscriber of the other end, whether or not the other end
+/// is open, as if it were a server which is always listening. Text and binary frames are both delivered as
+/// bytes. Closing an open end delivers [`crate::Error::WebsocketClosed`] to the other end.
+pub struct LoopbackWebsocketChannel {
+    stream_url: String,
+    channel_token: String,
+    open: AtomicBool,
+    subscriber: Subscriber,
+    peer: Subscriber,
+}
+
+impl Debug for LoopbackWebsocketChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoopbackWebsocketChannel")
+            .field("stream_url", &self.stream_url)
+            .field("channel_token", &"<redacted>")
+            .field("open", &self.open)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LoopbackWebsocketChannel {
+    /// Create a connected client and agent end, which both report `stream_url` and `channel_token`. Both
+    /// ends start closed.
+    #[must_use]
+    pub fn pair(stream_url: String, channel_token: String) -> (Self, Self) {
+        let client = Subscriber::default();
+        let agent = Subscriber::default();
+
+        (
+            Self {
+                stream_url: stream_url.clone(),
+                channel_token: channel_token.clone(),
+                open: AtomicBool::new(false),
+                subscriber: Arc::clone(&client),
+                peer: Arc::clone(&agent),
+            },
+            Self {
+                stream_url,
+                channel_token,
+                open: AtomicBool::new(false),
+                subscriber: agent,
+                peer: client,
+            },
+        )
+    }
+
+    /// Deliver `error` to the subscriber of the other end, as if its connection had failed with it. For
+    /// example, [`crate::Error::WebsocketPongTimeout`] makes a data channel on the other end reconnect.
+    pub fn send_error(&self, error: crate::Error) {
+        deliver(&self.peer, Err(error));
+    }
+}
+
+impl WebsocketChannel for LoopbackWebsocketChannel {
+    fn get_stream_url(&self) -> &str {
+        &self.stream_url
+    }
+
+    fn get_channel_token(&self) -> &str {
+        &self.channel_token
+    }
+
+    /// Close this end. Closing an end which is not open does nothing.
+    async fn close(&self) -> Result<(), crate::Error> {
+        if self.open.swap(false, Ordering::AcqRel) {
+            deliver(&self.peer, Err(crate::Error::WebsocketClosed));
+        }
+
+        Ok(())
+    }
+
+    /// Open this end. Opening an end which is already open does nothing.
+    async fn open(&self) -> Result<(), crate::Error> {
+        self.open.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Deliver `input` to the subscriber of the other end.
+    ///
+    /// ## Errors
+    ///
+    /// * [`crate::Error::InvalidWebsocketMessageType`] if `input_type` is not a known message type.
+    /// * [`crate::Error::InvalidTextMessage`] if a text message is not valid UTF-8.
+    /// * [`crate::Error::WebsocketNotOpen`] if this end is not open.
+    async fn send_message(&self, input: &[u8], input_type: u32) -> Result<(), crate::Error> {
+        let message = match input_type {
+            TEXT_MESSAGE => String::from_utf8(input.to_vec())
+                .map_err(crate::Error::InvalidTextMessage)?
+                .into(),
+            BINARY_MESSAGE => Bytes::copy_from_slice(input),
+            input_type => Err(crate::Error::InvalidWebsocketMessageType(input_type))?,
+        };
+
+        if !self.open.load(Ordering::Acquire) {
+            Err(crate::Error::WebsocketNotOpen)?;
+        }
+
+        deliver(&self.peer, Ok(message));
+
+        Ok(())
+    }
+
+    fn subscribe(&self) -> IncomingMessages {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *lock(&self.subscriber) = Some(sender);
+
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LoopbackWebsocketChannel;
+    use crate::{
+        data_channel::{DataChannel, DataChannelEvent, DefaultDataChannel},
+        message::{ClientMessage, MessageType, PayloadType},
+        websocket_channel::{BINARY_MESSAGE, TEXT_MESSAGE, WebsocketChannel},
+    };
+    use futures_util::FutureExt;
+    use std::time::Duration;
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    fn pair() -> (LoopbackWebsocketChannel, LoopbackWebsocketChannel) {
+        LoopbackWebsocketChannel::pair("wss://loopback".to_string(), "token".to_string())
+    }
+
+    #[test]
+    fn exchange_messages() {
+        let (client, agent) = pair();
+        let mut client_incoming = client.subscribe();
+        let mut agent_incoming = agent.subscribe();
+
+        assert!(matches!(
+            client.send_message(b"early", BINARY_MESSAGE).now_or_never(),
+            Some(Err(crate::Error::WebsocketNotOpen))
+        ));
+        assert!(matches!(
+            client.send_message(&[0xff], TEXT_MESSAGE).now_or_never(),
+            Some(Err(crate::Error::InvalidTextMessage(_)))
+        ));
+
+        client.open().now_or_never().unwrap().unwrap();
+        agent.open().now_or_never().unwrap().unwrap();
+        client
+            .send_message(b"token", TEXT_MESSAGE)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        agent
+            .send_message(&[0, 1, 2], BINARY_MESSAGE)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(agent_incoming.try_recv().unwrap().unwrap(), &b"token"[..]);
+        assert_eq!(client_incoming.try_recv().unwrap().unwrap(), &[0, 1, 2][..]);
+
+        agent.close().now_or_never().unwrap().unwrap();
+        assert!(matches!(
+            client_incoming.try_recv(),
+            Ok(Err(crate::Error::WebsocketClosed))
+        ));
+        assert!(matches!(
+            agent.send_message(b"late", BINARY_MESSAGE).now_or_never(),
+            Some(Err(crate::Error::WebsocketNotOpen))
+        ));
+
+        agent.close().now_or_never().unwrap().unwrap();
+        assert!(matches!(
+            client_incoming.try_recv(),
+            Err(TryRecvError::Empty)
+        ));
+    }
+
+    #[tokio::test]
+    async fn data_channel_session() {
+        let (client, agent) = pair();
+        let mut agent_incoming = agent.subscribe();
+        agent.open().await.unwrap();
+        let data_channel = DefaultDataChannel::new(
+            "client".to_string(),
+            "session".to_string(),
+            "instance".to_string(),
+            client,
+        );
+
+        data_channel
+            .open()
+            .await
+            .expect("Data channel should open.");
+        let open_data_channel_input: serde_json::Value = serde_json::from_slice(
+            &agent_incoming
+                .recv()
+                .await
+                .unwrap()
+                .expect("Token should be sent."),
+        )
+        .expect("Token message should be JSON.");
+        assert_eq!(open_data_channel_input["TokenValue"], "token");
+        assert_eq!(open_data_channel_input["ClientId"], "client");
+
+        let output = ClientMessage::builder()
+            .with_sequence_number(0)
+            .output_stream_data(PayloadType::Output, b"hello".to_vec())
+            .expect("Payload should fit.")
+            .build();
+        agent
+            .send_message(&output.serialize().unwrap(), BINARY_MESSAGE)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            data_channel
+                .receive_events()
+                .await
+                .expect("Output should be processed."),
+            vec![DataChannelEvent::StreamData {
+                payload_type: PayloadType::Output,
+                payload: b"hello".to_vec(),
+            }]
+        );
+
+        let ack = ClientMessage::deserialize(&agent_incoming.recv().await.unwrap().unwrap())
+            .expect("Acknowledgement should be a client message.");
+        assert_eq!(ack.message_type(), &MessageType::AcknowledgeMessage);
+        let content = ack
+            .deserialize_data_stream_acknowledge_content()
+            .expect("Acknowledgement content should be valid.");
+        assert_eq!(content.message_id(), output.message_id());
+        assert_eq!(content.sequence_number(), 0);
+
+        // A pong timeout makes the data channel reconnect, which sends the token again.
+        agent.send_error(crate::Error::WebsocketPongTimeout(Duration::from_secs(1)));
+        agent
+            .send_message(&output.serialize().unwrap(), BINARY_MESSAGE)
+            .await
+            .unwrap();
+        data_channel
+            .receive_events()
+            .await
+            .expect("Data channel should reconnect.");
+        assert!(matches!(
+            agent_incoming.recv().await,
+            Some(Err(crate::Error::WebsocketClosed))
+        ));
+        let open_data_channel_input: serde_json::Value =
+            serde_json::from_slice(&agent_incoming.recv().await.unwrap().unwrap())
+                .expect("Token message should be JSON.");
+        assert_eq!(open_data_channel_input["TokenValue"], "token");
+    }
+}
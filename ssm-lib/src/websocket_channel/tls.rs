@@ -0,0 +1,218 @@
+//! TLS settings for the websocket connection, for networks where the service certificate is not signed by
+//! a public CA, such as behind an inspecting proxy, and for endpoints which require client certificates.
+
+use rustls::{
+    ClientConfig, RootCertStore,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+use std::fmt::Debug;
+
+/// Which certificates [`crate::websocket_channel::DefaultWebsocketChannel`] trusts, and the certificate it
+/// presents if the server asks for one.
+///
+/// [`TlsConfig::new`] trusts the same public CAs as a connection without a custom configuration.
+pub struct TlsConfig {
+    webpki_roots: bool,
+    native_roots: bool,
+    root_certificates: Vec<CertificateDer<'static>>,
+    client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("webpki_roots", &self.webpki_roots)
+            .field("native_roots", &self.native_roots)
+            .field("root_certificates", &self.root_certificates.len())
+            .field(
+                "client_auth",
+                &self.client_auth.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            webpki_roots: true,
+            native_roots: false,
+            root_certificates: Vec::new(),
+            client_auth: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// Trust the public CAs bundled with the crate, from the Mozilla root program, without a client
+    /// certificate.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also trust the certificates in `pem`, for example the CA of an inspecting proxy. May be called more
+    /// than once.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidCertificate`] if `pem` cannot be parsed, or [`Error::NoCertificates`] if it
+    /// contains no certificates.
+    pub fn with_root_certificates_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        self.root_certificates.extend(parse_certificates(pem)?);
+        Ok(self)
+    }
+
+    /// Also trust the certificates in the trust store of the operating system.
+    #[must_use]
+    pub fn with_native_roots(mut self) -> Self {
+        self.native_roots = true;
+        self
+    }
+
+    /// Do not trust the bundled public CAs, only the native and explicitly added roots.
+    #[must_use]
+    pub fn without_webpki_roots(mut self) -> Self {
+        self.webpki_roots = false;
+        self
+    }
+
+    /// Present a client certificate if the server requests one. `certificate_chain_pem` holds the client
+    /// certificate followed by any intermediate certificates, and `private_key_pem` its private key.
+    ///
+    /// ## Errors
+    ///
+    /// Returns [`Error::InvalidCertificate`] or [`Error::NoCertificates`] if the certificate chain cannot be
+    /// used, or [`Error::InvalidPrivateKey`] if the private key cannot be parsed.
+    pub fn with_client_certificate_pem(
+        mut self,
+        certificate_chain_pem: &[u8],
+        private_key_pem: &[u8],
+    ) -> Result<Self, Error> {
+        let certificate_chain = parse_certificates(certificate_chain_pem)?;
+        let private_key =
+            PrivateKeyDer::from_pem_slice(private_key_pem).map_err(Error::InvalidPrivateKey)?;
+
+        self.client_auth = Some((certificate_chain, private_key));
+        Ok(self)
+    }
+
+    /// Build the rustls configuration.
+    ///
+    /// ## Errors
+    ///
+    /// * [`Error::NativeRoots`] if the native roots were requested but none could be loaded.
+    /// * [`Error::Rustls`] if a root certificate is invalid or the client certificate does not match its
+    ///   private key.
+    pub fn build(&self) -> Result<ClientConfig, Error> {
+        let mut roots = RootCertStore::empty();
+
+        if self.webpki_roots {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+
+        if self.native_roots {
+            let native = rustls_native_certs::load_native_certs();
+            if native.certs.is_empty() {
+                Err(Error::NativeRoots(
+                    native
+                        .errors
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("; "),
+                ))?;
+            }
+            for error in native.errors {
+                log::warn!("Skipping native root certificates: {error}");
+            }
+
+            let (added, ignored) = roots.add_parsable_certificates(native.certs);
+            log::debug!("Loaded {added} native root certificates, ignored {ignored}");
+        }
+
+        for certificate in &self.root_certificates {
+            roots.add(certificate.clone()).map_err(Error::Rustls)?;
+        }
+
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+
+        match &self.client_auth {
+            Some((certificate_chain, private_key)) => builder
+                .with_client_auth_cert(certificate_chain.clone(), private_key.clone_key())
+                .map_err(Error::Rustls),
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+}
+
+fn parse_certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certificates = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::InvalidCertificate)?;
+
+    if certificates.is_empty() {
+        Err(Error::NoCertificates)?;
+    }
+
+    Ok(certificates)
+}
+
+/// Errors produced while building a [`TlsConfig`].
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A PEM certificate could not be parsed.
+    #[error("Invalid PEM certificate: {0}")]
+    InvalidCertificate(#[source] rustls::pki_types::pem::Error),
+
+    /// A PEM input which should contain certificates contains none.
+    #[error("No certificates found in PEM input")]
+    NoCertificates,
+
+    /// A PEM private key could not be parsed.
+    #[error("Invalid PEM private key: {0}")]
+    InvalidPrivateKey(#[source] rustls::pki_types::pem::Error),
+
+    /// The native trust store could not be loaded.
+    #[error("Failed to load native root certificates: {0}")]
+    NativeRoots(String),
+
+    /// rustls rejected the configuration.
+    #[error("Invalid TLS configuration: {0}")]
+    Rustls(#[source] rustls::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, TlsConfig};
+
+    #[test]
+    fn reject_invalid_pem() {
+        assert!(matches!(
+            TlsConfig::new().with_root_certificates_pem(b"not a certificate"),
+            Err(Error::NoCertificates)
+        ));
+        assert!(matches!(
+            TlsConfig::new().with_root_certificates_pem(
+                b"-----BEGIN CERTIFICATE-----\nnot base64\n-----END CERTIFICATE-----\n"
+            ),
+            Err(Error::InvalidCertificate(_))
+        ));
+    }
+
+    #[test]
+    fn reject_mismatched_client_key() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["client".to_string()])
+            .expect("Certificate should be generated.");
+        let other_key = rcgen::KeyPair::generate().expect("Key should be generated.");
+
+        let config = TlsConfig::new()
+            .with_client_certificate_pem(
+                certificate.cert.pem().as_bytes(),
+                other_key.serialize_pem().as_bytes(),
+            )
+            .expect("Certificate and key should parse.");
+
+        assert!(matches!(config.build(), Err(Error::Rustls(_))));
+    }
+}